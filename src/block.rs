@@ -1,47 +1,129 @@
 mod builder;
 mod iterator;
 
-use std::sync::Arc;
 pub use builder::BlockBuilder;
 use bytes::{Buf, BufMut, Bytes};
 pub use iterator::BlockIterator;
 
-pub(crate) const SIZEOF_U16: usize = size_of::<u16>();
+pub(crate) const SIZEOF_U32: usize = size_of::<u32>();
+
+/// The most bytes a length takes once it is varint encoded (a u32 needs 5 groups of 7 bits).
+pub(crate) const MAX_VARINT_LEN: usize = 5;
+
+/// Number of entries between two restart points. Each restart entry stores its full key, every
+/// other entry only stores the suffix it does not share with the key before it.
+pub(crate) const RESTART_INTERVAL: usize = 16;
 
 /// A block is the smallest unit of read and caching in LSM tree. It is a collection of sorted
 /// key-value pairs.
 pub struct Block {
     pub(crate) data: Vec<u8>,
-    pub(crate) offsets: Vec<u16>,
+    /// Offsets of the restart entries in `data`.
+    pub(crate) offsets: Vec<u32>,
+    /// Whether every key is followed by its timestamp, false for the blocks of SsTables written
+    /// before keys were versioned, whose keys all read as `TS_DEFAULT`.
+    pub(crate) has_ts: bool,
 }
 
 impl Block {
     /// the encoded block has overall structure like
-    /// |              data section               |            offset section             |                                                      |
-    /// | key_len | key | value_len | value | ... | offset for first key-value pair | ... | len of all the offsets(the number of key-value pair) |
+    /// |                             data section                               |           restart section            |                          |
+    /// | shared_len | suffix_len | key suffix | ts (u64) | value_len | value | ... | restart offset (u32) | ... | number of restarts (u32) |
+    ///
+    /// `shared_len` is the number of leading bytes the key has in common with the previous key,
+    /// it is always 0 for the entries the restart offsets point at. The three lengths are varints
+    /// so a small entry stays small while keys and values are not limited to 64 KiB.
     pub fn encode(&self) -> Bytes {
         let mut buf = self.data.clone();
         let offsets_len = self.offsets.len();
         for offset in &self.offsets {
-            buf.put_u16(*offset);
+            buf.put_u32(*offset);
         }
-        // Adds number of elements at the end of the block
-        buf.put_u16(offsets_len as u16);
+        // Adds number of restart points at the end of the block
+        buf.put_u32(offsets_len as u32);
         buf.into()
     }
 
-    pub fn decode(data: &[u8]) -> Self {
-        // get number of elements in the block
-        let entry_offsets_len = (&data[data.len() - SIZEOF_U16..]).get_u16() as usize;
-        let data_end = data.len() - SIZEOF_U16 - entry_offsets_len * SIZEOF_U16;
-        let offsets_raw = &data[data_end..data.len() - SIZEOF_U16];
-        // get offset array
+    pub fn decode(data: &[u8], has_ts: bool) -> Self {
+        // get number of restart points in the block
+        let entry_offsets_len = (&data[data.len() - SIZEOF_U32..]).get_u32() as usize;
+        let data_end = data.len() - SIZEOF_U32 - entry_offsets_len * SIZEOF_U32;
+        let offsets_raw = &data[data_end..data.len() - SIZEOF_U32];
+        // get restart offset array
         let offsets = offsets_raw
-            .chunks(SIZEOF_U16)
-            .map(|mut x| x.get_u16())
+            .chunks(SIZEOF_U32)
+            .map(|mut x| x.get_u32())
             .collect();
         // retrieve data
         let data = data[0..data_end].to_vec();
-        Self { data, offsets }
+        Self { data, offsets, has_ts }
+    }
+}
+/// Encode `value` 7 bits at a time, lowest bits first, with the high bit set on every byte but
+/// the last one.
+pub(crate) fn put_varint(buf: &mut impl BufMut, mut value: usize) {
+    while value >= 0x80 {
+        buf.put_u8((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+pub(crate) fn get_varint(buf: &mut impl Buf) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = buf.get_u8();
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use super::{Block, BlockBuilder, BlockIterator, RESTART_INTERVAL};
+    use crate::key::{KeySlice, TS_RANGE_BEGIN};
+
+    #[test]
+    fn seek_to_key_across_restart_points() {
+        // 3 versions of each key, so the restart points land both between two keys and between
+        // two versions of the same key
+        let entries = (0..40)
+            .flat_map(|i| (1..=3).rev().map(move |ts| (format!("key_{:03}", i * 2), ts)))
+            .collect::<Vec<_>>();
+        let mut builder = BlockBuilder::new(1 << 20);
+        for (key, ts) in &entries {
+            assert!(builder.add(KeySlice::from_slice(key.as_bytes(), *ts), key.as_bytes()));
+        }
+        // encoded and decoded, like a block read from a file
+        let block = Arc::new(Block::decode(&builder.build().encode(), true));
+        assert_eq!(block.offsets.len(), entries.len().div_ceil(RESTART_INTERVAL));
+
+        let mut iter = BlockIterator::create_and_seek_to_first(block);
+        for i in 0..=81 {
+            for ts in [TS_RANGE_BEGIN, 3, 2, 1, 0] {
+                let target_key = format!("key_{:03}", i);
+                let target = KeySlice::from_slice(target_key.as_bytes(), ts);
+                iter.seek_to_key(target);
+                // the first entry at or after the target
+                let expected = entries
+                    .iter()
+                    .find(|(key, ts)| KeySlice::from_slice(key.as_bytes(), *ts) >= target);
+                match expected {
+                    Some((key, ts)) => {
+                        assert!(iter.is_valid(), "nothing found for {:?}", target);
+                        assert_eq!(iter.key(), KeySlice::from_slice(key.as_bytes(), *ts));
+                        assert_eq!(iter.value(), key.as_bytes());
+                    }
+                    None => assert!(!iter.is_valid(), "{:?} is after the last key", target),
+                }
+            }
+        }
+        iter.seek_to_key(KeySlice::from_slice(b"a", 0));
+        assert_eq!(iter.key(), KeySlice::from_slice(b"key_000", 3));
+    }
+}
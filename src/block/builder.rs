@@ -1,10 +1,14 @@
 use bytes::BufMut;
-use super::{Block, SIZEOF_U16};
+use crate::key::KeySlice;
+use super::{put_varint, Block, MAX_VARINT_LEN, RESTART_INTERVAL, SIZEOF_U32};
 
 pub struct BlockBuilder {
-    offsets: Vec<u16>,
+    offsets: Vec<u32>,
     data: Vec<u8>,
     block_size: usize,
+    // the previous user key and the number of entries so far, needed to prefix-compress the next key
+    last_key: Vec<u8>,
+    num_entries: usize,
 }
 
 impl BlockBuilder {
@@ -13,37 +17,58 @@ impl BlockBuilder {
             offsets: Vec::new(),
             data: Vec::new(),
             block_size,
+            last_key: Vec::new(),
+            num_entries: 0,
         }
     }
 
     fn estimated_size(&self) -> usize {
-        SIZEOF_U16 /* number of key-value pairs in the block */ +  self.offsets.len() * SIZEOF_U16 /* offsets */ + self.data.len()
+        SIZEOF_U32 /* number of restart points in the block */ +  self.offsets.len() * SIZEOF_U32 /* restart offsets */ + self.data.len()
         // key-value pairs
     }
 
     #[must_use]
-    pub fn add(&mut self, key: &[u8], value: &[u8]) -> bool {
+    pub fn add(&mut self, key: KeySlice, value: &[u8]) -> bool {
         assert!(!key.is_empty(), "key must not be empty");
-        if self.estimated_size() + key.len() + value.len() + SIZEOF_U16 * 3 /* key_len, value_len and offset */ > self.block_size
+        // An entry that does not fit into an empty block still gets one on its own, so a block can
+        // be larger than block_size when it holds a single big key or value.
+        if self.estimated_size() + key.key_len() + size_of::<u64>() /* ts */ + value.len() + MAX_VARINT_LEN * 3 /* shared_len, suffix_len and value_len */ + SIZEOF_U32 /* restart offset */ > self.block_size
             && !self.is_empty()
         {
             return false;
         }
-        // Add the offset of the data into the offset array.
-        self.offsets.push(self.data.len() as u16);
-        // Encode key length.
-        self.data.put_u16(key.len() as u16);
-        // Encode key content.
-        self.data.put(key);
+        // Every RESTART_INTERVAL entries we store the full key and remember where it starts, so a
+        // reader can binary search the restart points without decoding the whole block.
+        let shared = if self.num_entries.is_multiple_of(RESTART_INTERVAL) {
+            self.offsets.push(self.data.len() as u32);
+            0
+        } else {
+            self.last_key
+                .iter()
+                .zip(key.key_ref())
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        // Encode the length of the prefix shared with the previous key.
+        put_varint(&mut self.data, shared);
+        // Encode the length of the rest of the key and its content.
+        put_varint(&mut self.data, key.key_len() - shared);
+        self.data.put(&key.key_ref()[shared..]);
+        // Encode the timestamp, versions of the same key share the whole user key so they only
+        // cost the timestamp and the value.
+        self.data.put_u64(key.ts());
         // Encode value length.
-        self.data.put_u16(value.len() as u16);
+        put_varint(&mut self.data, value.len());
         // Encode value content.
         self.data.put(value);
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key.key_ref());
+        self.num_entries += 1;
         true
     }
 
     pub fn is_empty(&self) -> bool {
-        self.offsets.is_empty()
+        self.num_entries == 0
     }
 
     pub fn build(self) -> Block {
@@ -53,6 +78,7 @@ impl BlockBuilder {
         Block {
             data: self.data,
             offsets: self.offsets,
+            has_ts: true,
         }
     }
 }
@@ -1,13 +1,12 @@
-use std::borrow::Borrow;
 use std::sync::Arc;
 use bytes::Buf;
-use crate::block::{Block, SIZEOF_U16};
+use crate::block::{get_varint, Block};
+use crate::key::{KeySlice, KeyVec, TS_DEFAULT};
 
 pub struct BlockIterator {
     pub(crate) block: Arc<Block>,
-    pub(crate) key: Vec<u8>,
+    pub(crate) key: KeyVec,
     pub(crate) value_range: (usize, usize),
-    pub(crate) idx: usize,
 }
 
 impl BlockIterator {
@@ -15,34 +14,47 @@ impl BlockIterator {
         BlockIterator {
             block,
             value_range: (0, 0),
-            idx: 0,
-            key: Vec::new(),
+            key: KeyVec::new(),
         }
     }
 
+    // Decodes the entry at `offset`. Its key shares a prefix with `self.key`, so this only works
+    // when `self.key` holds the previous key or when `offset` is a restart point.
     fn seek_to_offset(&mut self, offset: usize) {
-        // getting the key_len and key
+        if offset >= self.block.data.len() {
+            self.key.clear();
+            self.value_range = (0, 0);
+            return
+        }
+        // getting the shared prefix length and the key suffix
         let mut data_from_start = &self.block.data[offset..];
-        let key_len = data_from_start.get_u16() as usize;
-        let key = data_from_start[..key_len].to_vec();
-        data_from_start.advance(key_len);
-        self.key.clear();
-        self.key.extend(key);
-        // getting the value_len and the value
-        let value_len = data_from_start.get_u16() as usize;
-        let value_offset_begin = offset + SIZEOF_U16 + key_len + SIZEOF_U16;
+        let shared = get_varint(&mut data_from_start);
+        let suffix_len = get_varint(&mut data_from_start);
+        let key = self.key.key_mut();
+        key.truncate(shared);
+        key.extend_from_slice(&data_from_start[..suffix_len]);
+        data_from_start.advance(suffix_len);
+        let ts = if self.block.has_ts {
+            data_from_start.get_u64()
+        } else {
+            TS_DEFAULT
+        };
+        self.key.set_ts(ts);
+        // getting the value_len and the value, the lengths are varints so the value starts
+        // wherever the buffer got to
+        let value_len = get_varint(&mut data_from_start);
+        let value_offset_begin = self.block.data.len() - data_from_start.remaining();
         let value_offset_end = value_offset_begin + value_len;
         self.value_range = (value_offset_begin, value_offset_end);
-        data_from_start.advance(value_len);
     }
 
-    fn seek_to(&mut self, idx: usize) {
-        if idx >= self.block.offsets.len() {
+    fn seek_to_restart(&mut self, restart: usize) {
+        if restart >= self.block.offsets.len() {
             self.key.clear();
             self.value_range = (0, 0);
             return
         }
-        let offset = self.block.offsets[idx] as usize;
+        let offset = self.block.offsets[restart] as usize;
         self.seek_to_offset(offset);
     }
 
@@ -51,7 +63,7 @@ impl BlockIterator {
     }
 
     pub fn seek_to_first(&mut self) {
-        self.seek_to(0);
+        self.seek_to_restart(0);
     }
 
     pub fn create_and_seek_to_first(block: Arc<Block>) -> Self {
@@ -60,31 +72,36 @@ impl BlockIterator {
         iter
     }
 
-    pub fn seek_to_key(&mut self, key: &[u8]) {
+    pub fn seek_to_key(&mut self, key: KeySlice) {
+        // Find the last restart point whose key is <= the target, the target (or the first key
+        // after it) is somewhere between that restart point and the next one.
         let mut low = 0;
         let mut high = self.block.offsets.len();
         while low < high {
             let mid = low + (high - low) / 2;
-            self.seek_to(mid);
+            self.seek_to_restart(mid);
             assert!(self.is_valid());
-            match self.key().cmp(key) {
+            match self.key().cmp(&key) {
                 std::cmp::Ordering::Less => low = mid + 1,
                 std::cmp::Ordering::Greater => high = mid,
                 std::cmp::Ordering::Equal => return,
             }
         }
-        self.seek_to(low);
+        self.seek_to_restart(low.saturating_sub(1));
+        while self.is_valid() && self.key() < key {
+            self.next();
+        }
     }
 
-    pub fn create_and_seek_to_key(block: Arc<Block>, key: &[u8]) -> Self {
+    pub fn create_and_seek_to_key(block: Arc<Block>, key: KeySlice) -> Self {
         let mut iter = Self::new(block);
         iter.seek_to_key(key);
         iter
     }
 
-    pub fn key(&self) -> &[u8] {
+    pub fn key(&self) -> KeySlice<'_> {
         debug_assert!(!self.key.is_empty(), "invalid iterator");
-        &self.key
+        self.key.as_key_slice()
     }
 
     pub fn value(&self) -> &[u8] {
@@ -93,7 +110,9 @@ impl BlockIterator {
     }
 
     pub fn next(&mut self) {
-        self.idx += 1;
-        self.seek_to(self.idx);
+        if !self.is_valid() {
+            return
+        }
+        self.seek_to_offset(self.value_range.1);
     }
-}
\ No newline at end of file
+}
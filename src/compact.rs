@@ -1,7 +1,886 @@
+mod leveled;
+mod simple_leveled;
+mod tiered;
+
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use crossbeam_channel::Receiver;
+use bytes::Bytes;
+pub use leveled::{LeveledCompactionController, LeveledCompactionOption, LeveledCompactionTask};
+pub use simple_leveled::{SimpleLeveledCompactionController, SimpleLeveledCompactionOption, SimpleLeveledCompactionTask};
+pub use tiered::{TieredCompactionController, TieredCompactionOption, TieredCompactionTask};
+use crate::iterator::concat_iterator::SstConcatIterator;
+use crate::iterator::merge_iterator::MergeIterator;
+use crate::iterator::two_merge_iterator::TwoMergeIterator;
+use crate::iterator::StorageIterator;
+use crate::key::{KeySlice, KeyVec};
+use crate::lsm_storage::{LsmStorageInner, LsmStorageState};
+use crate::manifest::ManifestRecord;
+use crate::table::{SsTable, SsTableBuilder, SsTableIterator};
+
 pub enum CompactionOption {
+    /// Never compact, every flushed SsTable just stays in L0.
+    NoCompaction,
+    /// Compact two adjacent levels as a whole once their size ratio is off.
+    Simple(SimpleLeveledCompactionOption),
+    /// Every flush creates a new sorted run, and sorted runs are merged by their sizes (also
+    /// known as universal compaction).
+    Tiered(TieredCompactionOption),
+    /// Compact one SsTable at a time into the next level, picking the level that is the furthest
+    /// over its target size.
+    Leveled(LeveledCompactionOption),
+}
 
+impl CompactionOption {
+    /// Reject the options a controller cannot work with, before a compaction thread runs into
+    /// them.
+    pub(crate) fn validate(&self) -> Result<()> {
+        match self {
+            CompactionOption::NoCompaction => Ok(()),
+            CompactionOption::Simple(option) => option.validate(),
+            CompactionOption::Tiered(option) => option.validate(),
+            CompactionOption::Leveled(option) => option.validate(),
+        }
+    }
 }
 
 pub enum CompactionController {
+    NoCompaction,
+    Simple(SimpleLeveledCompactionController),
+    Tiered(TieredCompactionController),
+    Leveled(LeveledCompactionController),
+}
+
+#[derive(Debug, Clone)]
+pub enum CompactionTask {
+    Simple(SimpleLeveledCompactionTask),
+    Tiered(TieredCompactionTask),
+    Leveled(LeveledCompactionTask),
+}
+
+impl CompactionController {
+    pub fn new(option: &CompactionOption) -> Self {
+        match option {
+            CompactionOption::NoCompaction => CompactionController::NoCompaction,
+            CompactionOption::Simple(option) => {
+                CompactionController::Simple(SimpleLeveledCompactionController::new(option.clone()))
+            }
+            CompactionOption::Tiered(option) => {
+                CompactionController::Tiered(TieredCompactionController::new(option.clone()))
+            }
+            CompactionOption::Leveled(option) => {
+                CompactionController::Leveled(LeveledCompactionController::new(option.clone()))
+            }
+        }
+    }
+
+    /// Whether a flushed memtable goes into L0, otherwise it becomes a new sorted run at the
+    /// front of `levels`.
+    pub fn flush_to_l0(&self) -> bool {
+        !matches!(self, CompactionController::Tiered(_))
+    }
+
+    pub fn generate_compaction_task(&self, snapshot: &LsmStorageState) -> Option<CompactionTask> {
+        match self {
+            CompactionController::NoCompaction => None,
+            CompactionController::Simple(controller) => controller
+                .generate_compaction_task(snapshot)
+                .map(CompactionTask::Simple),
+            CompactionController::Tiered(controller) => controller
+                .generate_compaction_task(snapshot)
+                .map(CompactionTask::Tiered),
+            CompactionController::Leveled(controller) => controller
+                .generate_compaction_task(snapshot)
+                .map(CompactionTask::Leveled),
+        }
+    }
+
+    pub fn apply_compaction_result(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &CompactionTask,
+        output: &[usize],
+    ) -> (LsmStorageState, Vec<usize>) {
+        match (self, task) {
+            (CompactionController::Simple(controller), CompactionTask::Simple(task)) => {
+                controller.apply_compaction_result(snapshot, task, output)
+            }
+            (CompactionController::Tiered(controller), CompactionTask::Tiered(task)) => {
+                controller.apply_compaction_result(snapshot, task, output)
+            }
+            (CompactionController::Leveled(controller), CompactionTask::Leveled(task)) => {
+                controller.apply_compaction_result(snapshot, task, output)
+            }
+            _ => unreachable!("the compaction task does not belong to this controller"),
+        }
+    }
+}
+
+impl LsmStorageInner {
+    /// Write everything that comes out of the iterator into new SsTables of about
+    /// `target_sst_size` each. The versions that no reader can get to anymore are dropped, and
+    /// so are the tombstones at the bottom level.
+    fn compact_generate_sst_from_iter(
+        &self,
+        mut iter: impl 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>,
+        compact_to_bottom_level: bool,
+    ) -> Result<Vec<Arc<SsTable>>> {
+        let mut builder = None;
+        let mut new_sst = Vec::new();
+        // a snapshot taken while the compaction runs reads at or above this watermark as well
+        let watermark = self.mvcc.watermark();
+        // the versions of the user key the iterator is on, they are written out together
+        let mut versions: Vec<(KeyVec, Bytes)> = Vec::new();
+        while iter.is_valid() {
+            if versions.first().is_some_and(|(key, _)| key.key_ref() != iter.key().key_ref()) {
+                let versions = std::mem::take(&mut versions);
+                self.add_key_versions(&mut builder, &mut new_sst, versions, watermark, compact_to_bottom_level)?;
+            }
+            versions.push((iter.key().to_key_vec(), Bytes::copy_from_slice(iter.value())));
+            iter.next()?;
+        }
+        self.add_key_versions(&mut builder, &mut new_sst, versions, watermark, compact_to_bottom_level)?;
+        if let Some(builder) = builder {
+            new_sst.push(self.build_compacted_sst(builder)?);
+        }
+        Ok(new_sst)
+    }
+
+    // Add the versions of one user key (newest first) that can still be read to the SsTable being
+    // built. The builder writes its file as it goes, so the id (and with it the file name) of an
+    // output SsTable is taken as soon as its first key shows up.
+    fn add_key_versions(
+        &self,
+        builder: &mut Option<(usize, SsTableBuilder)>,
+        new_sst: &mut Vec<Arc<SsTable>>,
+        mut versions: Vec<(KeyVec, Bytes)>,
+        watermark: u64,
+        compact_to_bottom_level: bool,
+    ) -> Result<()> {
+        // a reader at or above the watermark stops at the newest version it is allowed to see,
+        // so nobody gets past the newest version at or below the watermark
+        if let Some(idx) = versions.iter().position(|(key, _)| key.ts() <= watermark) {
+            versions.truncate(idx + 1);
+        }
+        // nothing is older than the bottom level, so a reader that stops at one of the oldest
+        // versions because it is a tombstone finds nothing without it as well
+        if compact_to_bottom_level {
+            while versions.last().is_some_and(|(_, value)| value.is_empty()) {
+                versions.pop();
+            }
+        }
+        if versions.is_empty() {
+            return Ok(());
+        }
+        // a lookup in a level only checks the one SsTable whose key range covers the key, so the
+        // versions of a key must never be split between two SsTables
+        if builder
+            .as_ref()
+            .is_some_and(|(_, inner)| inner.estimated_size() >= self.config.target_sst_size)
+        {
+            new_sst.push(self.build_compacted_sst(builder.take().unwrap())?);
+        }
+        if builder.is_none() {
+            let sst_id = self.next_sst_id();
+            *builder = Some((sst_id, self.new_sst_builder(sst_id)?));
+        }
+        let (_, inner) = builder.as_mut().unwrap();
+        for (key, value) in versions {
+            inner.add(key.as_key_slice(), &value)?;
+        }
+        Ok(())
+    }
+
+    fn build_compacted_sst(&self, (sst_id, builder): (usize, SsTableBuilder)) -> Result<Arc<SsTable>> {
+        Ok(Arc::new(builder.build(sst_id, Some(self.block_cache.clone()))?))
+    }
+
+    /// Merge SsTables of an upper level (None for L0) into SsTables of the level below it.
+    fn compact_two_levels(
+        &self,
+        snapshot: &LsmStorageState,
+        upper_level: Option<usize>,
+        upper_level_sst_ids: &[usize],
+        lower_level_sst_ids: &[usize],
+        compact_to_bottom_level: bool,
+    ) -> Result<Vec<Arc<SsTable>>> {
+        let lower_ssts = lower_level_sst_ids
+            .iter()
+            .map(|id| snapshot.sstables[id].clone())
+            .collect::<Vec<_>>();
+        let lower_iter = SstConcatIterator::create_and_seek_to_first(lower_ssts)?;
+        match upper_level {
+            Some(_) => {
+                let upper_ssts = upper_level_sst_ids
+                    .iter()
+                    .map(|id| snapshot.sstables[id].clone())
+                    .collect::<Vec<_>>();
+                let upper_iter = SstConcatIterator::create_and_seek_to_first(upper_ssts)?;
+                self.compact_generate_sst_from_iter(
+                    TwoMergeIterator::create(upper_iter, lower_iter)?,
+                    compact_to_bottom_level,
+                )
+            }
+            None => {
+                // L0 SsTables overlap, the order of upper_level_sst_ids (newest first) makes the
+                // newest value win in the merge iterator
+                let mut upper_iters = Vec::with_capacity(upper_level_sst_ids.len());
+                for id in upper_level_sst_ids.iter() {
+                    upper_iters.push(Box::new(SsTableIterator::create_and_seek_to_first(
+                        snapshot.sstables[id].clone(),
+                    )?));
+                }
+                let upper_iter = MergeIterator::create(upper_iters);
+                self.compact_generate_sst_from_iter(
+                    TwoMergeIterator::create(upper_iter, lower_iter)?,
+                    compact_to_bottom_level,
+                )
+            }
+        }
+    }
+
+    fn compact(&self, task: &CompactionTask) -> Result<Vec<Arc<SsTable>>> {
+        let snapshot = {
+            let guard = self.state.read();
+            guard.clone()
+        };
+        match task {
+            CompactionTask::Simple(task) => self.compact_two_levels(
+                &snapshot,
+                task.upper_level,
+                &task.upper_level_sst_ids,
+                &task.lower_level_sst_ids,
+                task.is_lower_level_bottom_level,
+            ),
+            CompactionTask::Leveled(task) => self.compact_two_levels(
+                &snapshot,
+                task.upper_level,
+                &task.upper_level_sst_ids,
+                &task.lower_level_sst_ids,
+                task.is_lower_level_bottom_level,
+            ),
+            CompactionTask::Tiered(task) => {
+                // every tier is a sorted run, and the newer tiers come first so they win
+                let mut tier_iters = Vec::with_capacity(task.tiers.len());
+                for (_, tier_sst_ids) in task.tiers.iter() {
+                    let tier_ssts = tier_sst_ids
+                        .iter()
+                        .map(|id| snapshot.sstables[id].clone())
+                        .collect::<Vec<_>>();
+                    tier_iters.push(Box::new(SstConcatIterator::create_and_seek_to_first(tier_ssts)?));
+                }
+                self.compact_generate_sst_from_iter(
+                    MergeIterator::create(tier_iters),
+                    task.bottom_tier_included,
+                )
+            }
+        }
+    }
+
+    /// Run one compaction if the controller thinks the current shape of the tree needs one.
+    fn trigger_compaction(&self) -> Result<()> {
+        let _compaction_lock = self.compaction_lock.lock();
+        let snapshot = {
+            let guard = self.state.read();
+            guard.clone()
+        };
+        let Some(task) = self.compaction_controller.generate_compaction_task(&snapshot) else {
+            return Ok(());
+        };
+        let sstables = self.compact(&task)?;
+        self.install_compaction_result(sstables, |snapshot, output| {
+            self.compaction_controller.apply_compaction_result(snapshot, &task, output)
+        })
+    }
+
+    /// Merge every SsTable in L0 and in all the levels into one sorted run at the bottom level.
+    /// Nothing is older than that, so tombstones and shadowed versions are all dropped.
+    pub(crate) fn force_full_compaction(&self) -> Result<()> {
+        let _compaction_lock = self.compaction_lock.lock();
+        let snapshot = {
+            let guard = self.state.read();
+            guard.clone()
+        };
+        let l0_sstables = snapshot.l0_sstables.clone();
+        let levels = snapshot.levels.clone();
+        if l0_sstables.is_empty() && levels.iter().all(|(_, ssts)| ssts.is_empty()) {
+            return Ok(());
+        }
+
+        let mut l0_iters = Vec::with_capacity(l0_sstables.len());
+        for id in l0_sstables.iter() {
+            l0_iters.push(Box::new(SsTableIterator::create_and_seek_to_first(
+                snapshot.sstables[id].clone(),
+            )?));
+        }
+        let mut level_iters = Vec::with_capacity(levels.len());
+        for (_, level_sst_ids) in levels.iter() {
+            let level_ssts = level_sst_ids
+                .iter()
+                .map(|id| snapshot.sstables[id].clone())
+                .collect::<Vec<_>>();
+            level_iters.push(Box::new(SstConcatIterator::create_and_seek_to_first(level_ssts)?));
+        }
+        let iter = TwoMergeIterator::create(MergeIterator::create(l0_iters), MergeIterator::create(level_iters))?;
+        let sstables = self.compact_generate_sst_from_iter(iter, true)?;
+
+        let flush_to_l0 = self.compaction_controller.flush_to_l0();
+        self.install_compaction_result(sstables, |snapshot, output| {
+            let mut snapshot = snapshot.clone();
+            let mut files_to_remove = l0_sstables.clone();
+            // only what was compacted goes away, SsTables flushed in the meantime stay
+            snapshot.l0_sstables.retain(|id| !l0_sstables.contains(id));
+            if flush_to_l0 {
+                for (_, level_ssts) in snapshot.levels.iter_mut() {
+                    files_to_remove.append(level_ssts);
+                }
+                snapshot.levels.last_mut().unwrap().1 = output.to_vec();
+            } else {
+                // the tiers flushed in the meantime are in front of the compacted ones
+                let new_tiers = snapshot.levels.len() - levels.len();
+                for (_, tier_ssts) in snapshot.levels.drain(new_tiers..) {
+                    files_to_remove.extend(tier_ssts);
+                }
+                if !output.is_empty() {
+                    snapshot.levels.push((output[0], output.to_vec()));
+                }
+            }
+            (snapshot, files_to_remove)
+        })
+    }
+
+    /// Make the output of a compaction visible: `apply` turns the latest state and the ids of
+    /// the new SsTables into the new state and the ids of the SsTables that are not used anymore.
+    fn install_compaction_result(
+        &self,
+        sstables: Vec<Arc<SsTable>>,
+        apply: impl FnOnce(&LsmStorageState, &[usize]) -> (LsmStorageState, Vec<usize>),
+    ) -> Result<()> {
+        let output = sstables.iter().map(|sst| sst.sst_id()).collect::<Vec<_>>();
+        let files_to_remove = {
+            let state_lock = self.state_lock.lock();
+            // flushes may have happened while compacting, so the result is applied to the
+            // latest state instead of the snapshot the task was generated from
+            let mut snapshot = self.state.read().as_ref().clone();
+            for sst in sstables {
+                snapshot.sstables.insert(sst.sst_id(), sst);
+            }
+            let (mut snapshot, files_to_remove) = apply(&snapshot, &output);
+            for id in files_to_remove.iter() {
+                snapshot.sstables.remove(id);
+            }
+
+            // the new SsTables have to be durable before the manifest points to them
+            self.sync_dir()?;
+            self.manifest.as_ref().unwrap().add_record(
+                &state_lock,
+                ManifestRecord::Compaction {
+                    l0_sstables: snapshot.l0_sstables.clone(),
+                    levels: snapshot.levels.clone(),
+                },
+            )?;
+            *self.state.write() = Arc::new(snapshot);
+            files_to_remove
+        };
+
+        // readers that took a snapshot before the swap still hold the Arc<SsTable> and its open
+        // file, so removing the files here does not break them
+        for id in files_to_remove {
+            std::fs::remove_file(self.path_of_sst(id))?;
+        }
+        self.sync_dir()?;
+        Ok(())
+    }
+
+    // A panic in a controller would end the compaction thread without anybody noticing, and L0
+    // would grow forever. The state is only ever swapped as a whole, so a compaction that panics
+    // leaves nothing half done behind and the panic can be reported like any other error.
+    fn trigger_compaction_catch_panic(&self) -> Result<()> {
+        std::panic::catch_unwind(AssertUnwindSafe(|| self.trigger_compaction())).unwrap_or_else(|panic| {
+            let message = panic
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            Err(anyhow!("compaction panicked: {}", message))
+        })
+    }
+
+    /// Spawn the thread that compacts in the background, it stops once something is sent
+    /// through `stop` or the sender is dropped.
+    pub(crate) fn spawn_compaction_thread(self: &Arc<Self>, stop: Receiver<()>) -> Result<Option<JoinHandle<()>>> {
+        if let CompactionOption::NoCompaction = self.config.compaction_option {
+            return Ok(None);
+        }
+        let this = self.clone();
+        let handle = std::thread::Builder::new()
+            .name("lsm-compaction".to_string())
+            .spawn(move || {
+                let ticker = crossbeam_channel::tick(Duration::from_millis(50));
+                loop {
+                    crossbeam_channel::select! {
+                        recv(ticker) -> _ => if let Err(e) = this.trigger_compaction_catch_panic() {
+                            this.set_background_error(e);
+                            return;
+                        },
+                        recv(stop) -> _ => return,
+                    }
+                }
+            })?;
+        Ok(Some(handle))
+    }
+}
 
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::ops::Bound;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use anyhow::Result;
+    use super::{CompactionOption, LeveledCompactionOption, SimpleLeveledCompactionOption, TieredCompactionOption};
+    use crate::iterator::StorageIterator;
+    use crate::lsm_storage::{BackgroundError, LsmStorageConfig, MiniLsm};
+    use crate::table::{SsTableIterator, TableProperties};
+
+    fn simple() -> CompactionOption {
+        CompactionOption::Simple(SimpleLeveledCompactionOption {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+        })
+    }
+
+    fn tiered() -> CompactionOption {
+        CompactionOption::Tiered(TieredCompactionOption {
+            num_tiers: 5,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+        })
+    }
+
+    fn scan_all(db: &MiniLsm) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut iter = db.scan(Bound::Unbounded, Bound::Unbounded)?;
+        let mut entries = Vec::new();
+        while iter.is_valid() {
+            entries.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.next()?;
+        }
+        Ok(entries)
+    }
+
+    // Run the compactions the controller asks for until it is happy with the shape, the
+    // background thread may run some of them as well.
+    fn compact_until_done(db: &MiniLsm) -> Result<()> {
+        loop {
+            let snapshot = db.inner.state.read().clone();
+            if db.inner.compaction_controller.generate_compaction_task(&snapshot).is_none() {
+                return Ok(());
+            }
+            db.inner.trigger_compaction()?;
+        }
+    }
+
+    #[test]
+    fn simple_leveled_compaction_keeps_the_latest_values() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = || LsmStorageConfig {
+            compaction_option: simple(),
+            ..Default::default()
+        };
+        let db = MiniLsm::open(dir.path(), config())?;
+        let key = |i: usize| format!("key_{:03}", i).into_bytes();
+        for round in 0..6 {
+            for i in (round..100).step_by(round + 1) {
+                db.put(&key(i), format!("value_{}_{}", round, i).as_bytes())?;
+            }
+            if round == 5 {
+                for i in (0..100).step_by(10) {
+                    db.delete(&key(i))?;
+                }
+            }
+            db.force_flush()?;
+        }
+        compact_until_done(&db)?;
+        let snapshot = db.inner.state.read().clone();
+        assert!(snapshot.l0_sstables.len() < 2);
+        assert!(snapshot.levels.iter().any(|(_, ssts)| !ssts.is_empty()));
+
+        // the value of the last round that wrote each key, unless it was deleted
+        let expected = (0..100)
+            .filter(|i| i % 10 != 0)
+            .map(|i| {
+                let round = (0..6).rev().find(|round| i >= *round && (i - round) % (round + 1) == 0).unwrap();
+                (key(i), format!("value_{}_{}", round, i).into_bytes())
+            })
+            .collect::<Vec<_>>();
+        let check = |db: &MiniLsm| -> Result<()> {
+            assert_eq!(scan_all(db)?, expected);
+            for i in 0..100 {
+                let value = expected.iter().find(|(k, _)| *k == key(i)).map(|(_, v)| v.clone());
+                assert_eq!(db.get(&key(i))?.map(|v| v.to_vec()), value);
+            }
+            Ok(())
+        };
+        check(&db)?;
+        db.close()?;
+        drop(db);
+        check(&*MiniLsm::open(dir.path(), config())?)
+    }
+
+    #[test]
+    fn tiered_compaction_keeps_the_latest_values() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = || LsmStorageConfig {
+            compaction_option: tiered(),
+            ..Default::default()
+        };
+        let db = MiniLsm::open(dir.path(), config())?;
+        let key = |i: usize| format!("key_{:03}", i).into_bytes();
+        let mut expected = BTreeMap::new();
+        for round in 0..6 {
+            for i in (round..100).step_by(round + 1) {
+                let value = format!("value_{}_{}", round, i).into_bytes();
+                db.put(&key(i), &value)?;
+                expected.insert(key(i), value);
+            }
+            if round == 5 {
+                for i in (0..100).step_by(10) {
+                    db.delete(&key(i))?;
+                    expected.remove(&key(i));
+                }
+            }
+            let tiers = db.inner.state.read().levels.clone();
+            db.force_flush()?;
+            if round < 5 {
+                // up to num_tiers there is nothing to compact, every flush is a new tier in
+                // front of the others and L0 stays empty
+                let snapshot = db.inner.state.read().clone();
+                assert!(snapshot.l0_sstables.is_empty());
+                assert_eq!(snapshot.levels[1..], tiers[..]);
+                assert_eq!(snapshot.levels[0].1, vec![snapshot.levels[0].0]);
+            }
+        }
+
+        // the five tiers above the bottom one are 500% of it, so everything is merged
+        compact_until_done(&db)?;
+        let snapshot = db.inner.state.read().clone();
+        assert!(snapshot.l0_sstables.is_empty());
+        assert_eq!(snapshot.levels.len(), 1);
+
+        let expected = expected.into_iter().collect::<Vec<_>>();
+        let check = |db: &MiniLsm| -> Result<()> {
+            assert_eq!(scan_all(db)?, expected);
+            for i in 0..100 {
+                let value = expected.iter().find(|(k, _)| *k == key(i)).map(|(_, v)| v.clone());
+                assert_eq!(db.get(&key(i))?.map(|v| v.to_vec()), value);
+            }
+            Ok(())
+        };
+        check(&db)?;
+        db.close()?;
+        drop(db);
+        check(&*MiniLsm::open(dir.path(), config())?)
+    }
+
+    #[test]
+    fn leveled_compaction_keeps_the_latest_values() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = || LsmStorageConfig {
+            target_sst_size: 64 << 10,
+            compaction_option: CompactionOption::Leveled(LeveledCompactionOption {
+                level_size_multiplier: 10,
+                base_level_size_mb: 1,
+                max_levels: 4,
+                level0_file_num_compaction_trigger: 2,
+            }),
+            ..Default::default()
+        };
+        let db = MiniLsm::open(dir.path(), config())?;
+        let key = |i: usize| format!("key_{:04}", i).into_bytes();
+        let value = |round: usize, i: usize| format!("value_{}_{}_{}", round, i, "x".repeat(1000)).into_bytes();
+        let mut expected = BTreeMap::new();
+
+        // about 2MB, enough for the bottom level to get a level with a target size above it
+        for i in 0..2000 {
+            let value = value(0, i);
+            db.put(&key(i), &value)?;
+            expected.insert(key(i), value);
+        }
+        db.force_flush()?;
+        compact_until_done(&db)?;
+        let bottom_ssts = db.inner.state.read().levels[3].1.clone();
+        assert!(bottom_ssts.len() > 10);
+
+        // more than the target size of L3, which gets drained into the bottom level, but the narrow
+        // ranges only overlap with a few SsTables of the levels below, the compacted SsTables land
+        // between the ones left alone
+        for (round, range) in [(1, 1000..1100), (2, 500..550), (3, 1040..1600), (4, 20..40)] {
+            for i in range.clone() {
+                if i % 10 == 0 {
+                    db.delete(&key(i))?;
+                    expected.remove(&key(i));
+                } else {
+                    let value = value(round, i);
+                    db.put(&key(i), &value)?;
+                    expected.insert(key(i), value);
+                }
+            }
+            db.force_flush()?;
+        }
+        compact_until_done(&db)?;
+
+        // every level is still sorted by key, with SsTables that do not overlap
+        let snapshot = db.inner.state.read().clone();
+        for (_, ssts) in snapshot.levels.iter() {
+            for pair in ssts.windows(2) {
+                assert!(snapshot.sstables[&pair[0]].last_key() < snapshot.sstables[&pair[1]].first_key());
+            }
+        }
+        // and the bottom level was only partially rewritten
+        let bottom = &snapshot.levels[3].1;
+        assert!(bottom_ssts.iter().any(|id| bottom.contains(id)));
+        assert!(bottom_ssts.iter().any(|id| !bottom.contains(id)));
+
+        let expected = expected.into_iter().collect::<Vec<_>>();
+        let check = |db: &MiniLsm| -> Result<()> {
+            assert_eq!(scan_all(db)?, expected);
+            for i in (0..2000).step_by(7).chain(1000..1100) {
+                let value = expected.iter().find(|(k, _)| *k == key(i)).map(|(_, v)| v.clone());
+                assert_eq!(db.get(&key(i))?.map(|v| v.to_vec()), value);
+            }
+            Ok(())
+        };
+        check(&db)?;
+        db.close()?;
+        drop(db);
+        check(&*MiniLsm::open(dir.path(), config())?)
+    }
+
+    #[test]
+    fn full_compaction_leaves_one_sorted_run_of_live_values() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = MiniLsm::open(
+            dir.path(),
+            LsmStorageConfig {
+                // a few SsTables in the output
+                block_size: 128,
+                target_sst_size: 512,
+                compaction_option: CompactionOption::NoCompaction,
+                ..Default::default()
+            },
+        )?;
+        let key = |i: usize| format!("key_{:03}", i).into_bytes();
+        for i in 0..100 {
+            db.put(&key(i), b"old")?;
+        }
+        db.force_flush()?;
+        for i in (0..100).step_by(2) {
+            db.put(&key(i), b"new")?;
+        }
+        for i in (0..100).step_by(3) {
+            db.delete(&key(i))?;
+        }
+        db.force_flush()?;
+        db.force_full_compaction()?;
+
+        let snapshot = db.inner.state.read().clone();
+        assert!(snapshot.l0_sstables.is_empty());
+        let (_, bottom) = snapshot.levels.last().unwrap();
+        assert!(bottom.len() > 1);
+        assert_eq!(snapshot.sstables.len(), bottom.len());
+        let live = (0..100).filter(|i| i % 3 != 0).collect::<Vec<_>>();
+        let value = |i: usize| if i.is_multiple_of(2) { b"new".to_vec() } else { b"old".to_vec() };
+        let expected = live.iter().map(|&i| (key(i), value(i))).collect::<Vec<_>>();
+        // a single version of every live key, and not a single tombstone
+        let expected_entries = live
+            .iter()
+            .map(|&i| {
+                let ts = if i % 2 == 0 { 101 + i as u64 / 2 } else { 1 + i as u64 };
+                (key(i), ts, value(i))
+            })
+            .collect::<Vec<_>>();
+        let mut entries = Vec::new();
+        for id in bottom {
+            let mut iter = SsTableIterator::create_and_seek_to_first(snapshot.sstables[id].clone())?;
+            while iter.is_valid() {
+                entries.push((iter.key().key_ref().to_vec(), iter.key().ts(), iter.value().to_vec()));
+                iter.next()?;
+            }
+        }
+        assert_eq!(entries, expected_entries);
+
+        assert_eq!(scan_all(&db)?, expected);
+        for i in 0..100 {
+            let expected = (i % 3 != 0).then(|| value(i));
+            assert_eq!(db.get(&key(i))?.map(|v| v.to_vec()), expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sstables_flushed_during_a_full_compaction_are_kept() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = MiniLsm::open(
+            dir.path(),
+            LsmStorageConfig {
+                compaction_option: CompactionOption::NoCompaction,
+                ..Default::default()
+            },
+        )?;
+        let key = |i: usize| format!("key_{:05}", i).into_bytes();
+        for i in 0..5000 {
+            db.put(&key(i), b"compacted")?;
+            if i % 500 == 0 {
+                db.force_flush()?;
+            }
+        }
+        db.force_flush()?;
+        let compaction = {
+            let db = db.clone();
+            std::thread::spawn(move || db.force_full_compaction())
+        };
+        // whatever lands between the snapshot of the compaction and its result has to survive
+        let mut i = 5000;
+        while !compaction.is_finished() || i == 5000 {
+            db.put(&key(i), b"flushed")?;
+            db.force_flush()?;
+            i += 1;
+        }
+        compaction.join().unwrap()?;
+        for i in 0..i {
+            let expected: &[u8] = if i < 5000 { b"compacted" } else { b"flushed" };
+            assert_eq!(db.get(&key(i))?.as_deref(), Some(expected));
+        }
+        let snapshot = db.inner.state.read().clone();
+        assert!(snapshot.l0_sstables.iter().all(|id| snapshot.sstables.contains_key(id)));
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_output_is_split_at_the_target_size_between_keys() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let target_sst_size = 4096;
+        let db = MiniLsm::open(
+            dir.path(),
+            LsmStorageConfig {
+                block_size: 256,
+                target_sst_size,
+                compaction_option: CompactionOption::NoCompaction,
+                ..Default::default()
+            },
+        )?;
+        // the snapshot keeps every version written after it, so each key has more versions than
+        // fit into a block
+        let snapshot = db.snapshot();
+        for version in 0..10 {
+            for i in 0..40 {
+                db.put(format!("key_{:02}", i).as_bytes(), format!("{:02}", version).repeat(16).as_bytes())?;
+            }
+        }
+        db.force_flush()?;
+        db.force_full_compaction()?;
+
+        let state = db.inner.state.read().clone();
+        let (_, bottom) = state.levels.last().unwrap();
+        let ssts = bottom.iter().map(|id| state.sstables[id].clone()).collect::<Vec<_>>();
+        assert!(ssts.len() > 2);
+        for sst in &ssts[..ssts.len() - 1] {
+            // cut once the blocks reach the target, a key with all its versions past it
+            assert!(sst.table_size() >= target_sst_size as u64);
+            assert!(sst.table_size() < 2 * target_sst_size as u64);
+        }
+        for pair in ssts.windows(2) {
+            assert!(pair[0].last_key().key_ref() < pair[1].first_key().key_ref());
+        }
+        let num_entries = ssts.iter().map(|sst| sst.properties().unwrap().num_entries).sum::<u64>();
+        assert_eq!(num_entries, 40 * 10);
+        for i in 0..40 {
+            assert_eq!(db.get(format!("key_{:02}", i).as_bytes())?, Some("09".repeat(16).into()));
+        }
+        drop(snapshot);
+        Ok(())
+    }
+
+    #[test]
+    fn versions_below_the_watermark_are_dropped_once_no_snapshot_needs_them() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = MiniLsm::open(
+            dir.path(),
+            LsmStorageConfig {
+                compaction_option: CompactionOption::NoCompaction,
+                ..Default::default()
+            },
+        )?;
+        let num_entries = |db: &MiniLsm| {
+            let state = db.inner.state.read().clone();
+            let ssts = state.levels.iter().flat_map(|(_, ssts)| ssts.iter());
+            let properties = ssts.map(|id| state.sstables[id].properties().unwrap().clone()).collect::<Vec<_>>();
+            let total = |f: fn(&TableProperties) -> u64| properties.iter().map(f).sum::<u64>();
+            (total(|p| p.num_entries), total(|p| p.num_tombstones))
+        };
+        db.put(b"a", b"0")?;
+        db.put(b"a", b"1")?;
+        db.put(b"b", b"1")?;
+        let snapshot = db.snapshot();
+        db.put(b"a", b"2")?;
+        db.put(b"a", b"3")?;
+        db.delete(b"b")?;
+        db.force_flush()?;
+
+        // the versions the snapshot reads survive with everything after them, only the version
+        // the snapshot already skips is dropped
+        db.force_full_compaction()?;
+        assert_eq!(num_entries(&db), (5, 1));
+        assert_eq!(snapshot.get(b"a")?.as_deref(), Some(&b"1"[..]));
+        assert_eq!(snapshot.get(b"b")?.as_deref(), Some(&b"1"[..]));
+
+        // without the snapshot only the latest value of a is left, and b is gone altogether
+        drop(snapshot);
+        db.force_full_compaction()?;
+        assert_eq!(num_entries(&db), (1, 0));
+        assert_eq!(db.get(b"a")?.as_deref(), Some(&b"3"[..]));
+        assert_eq!(db.get(b"b")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn controller_panic_is_a_background_error() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = MiniLsm::open(
+            dir.path(),
+            LsmStorageConfig {
+                compaction_option: simple(),
+                ..Default::default()
+            },
+        )?;
+        for key in [b"a", b"b"] {
+            db.put(key, b"1")?;
+            db.force_flush()?;
+        }
+        // a shape the controller does not expect, it looks past the end of the levels
+        {
+            let _state_lock = db.inner.state_lock.lock();
+            let mut guard = db.inner.state.write();
+            let mut snapshot = guard.as_ref().clone();
+            snapshot.levels.clear();
+            *guard = Arc::new(snapshot);
+        }
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let e = loop {
+            assert!(Instant::now() < deadline, "the compaction panic never reached a writer");
+            if let Err(e) = db.put(b"c", b"1") {
+                break e;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert!(e.downcast_ref::<BackgroundError>().is_some());
+        assert!(format!("{:#}", e).contains("compaction panicked"));
+        assert!(db.close().unwrap_err().downcast_ref::<BackgroundError>().is_some());
+        Ok(())
+    }
+}
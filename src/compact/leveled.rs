@@ -0,0 +1,303 @@
+use std::collections::HashSet;
+use anyhow::{ensure, Result};
+use crate::lsm_storage::LsmStorageState;
+
+#[derive(Debug, Clone)]
+pub struct LeveledCompactionOption {
+    /// The target size of a level is this many times of the level above it.
+    pub level_size_multiplier: usize,
+    /// Levels whose target size would be below this are left empty, L0 is compacted into the
+    /// first level that is not.
+    pub base_level_size_mb: usize,
+    pub max_levels: usize,
+    /// L0 is compacted into the base level once it has this many SsTables.
+    pub level0_file_num_compaction_trigger: usize,
+}
+
+impl LeveledCompactionOption {
+    pub(crate) fn validate(&self) -> Result<()> {
+        ensure!(self.max_levels >= 1, "leveled compaction needs at least one level");
+        ensure!(self.level_size_multiplier >= 1, "level_size_multiplier must be at least 1");
+        // with 0 an empty L0 would be compacted over and over
+        ensure!(
+            self.level0_file_num_compaction_trigger >= 1,
+            "level0_file_num_compaction_trigger must be at least 1"
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LeveledCompactionTask {
+    // None means the upper level is L0
+    pub upper_level: Option<usize>,
+    pub upper_level_sst_ids: Vec<usize>,
+    pub lower_level: usize,
+    pub lower_level_sst_ids: Vec<usize>,
+    pub is_lower_level_bottom_level: bool,
+}
+
+pub struct LeveledCompactionController {
+    option: LeveledCompactionOption,
+}
+
+impl LeveledCompactionController {
+    pub fn new(option: LeveledCompactionOption) -> Self {
+        Self { option }
+    }
+
+    /// The SsTables of `in_level` whose key ranges overlap with the key range covered by `sst_ids`.
+    /// Only the user keys are compared, every version of a key has to end up in the same SsTable.
+    fn find_overlapping_ssts(&self, snapshot: &LsmStorageState, sst_ids: &[usize], in_level: usize) -> Vec<usize> {
+        let begin_key = sst_ids
+            .iter()
+            .map(|id| snapshot.sstables[id].first_key().key_ref())
+            .min();
+        let end_key = sst_ids
+            .iter()
+            .map(|id| snapshot.sstables[id].last_key().key_ref())
+            .max();
+        // nothing overlaps with an empty range
+        let (Some(begin_key), Some(end_key)) = (begin_key, end_key) else {
+            return Vec::new();
+        };
+        snapshot.levels[in_level - 1]
+            .1
+            .iter()
+            .filter(|id| {
+                let sst = &snapshot.sstables[*id];
+                !(sst.last_key().key_ref() < begin_key || sst.first_key().key_ref() > end_key)
+            })
+            .copied()
+            .collect()
+    }
+
+    /// The target sizes are computed from the bottom level up, the bottom level keeps its real
+    /// size and every level above it is `level_size_multiplier` times smaller, until the size drops
+    /// below `base_level_size_mb`. Then the level with the highest `real size / target size` is
+    /// compacted one SsTable at a time.
+    pub fn generate_compaction_task(&self, snapshot: &LsmStorageState) -> Option<LeveledCompactionTask> {
+        let max_levels = self.option.max_levels;
+        let base_level_size_bytes = self.option.base_level_size_mb as u64 * 1024 * 1024;
+
+        let real_level_sizes = snapshot
+            .levels
+            .iter()
+            .map(|(_, ssts)| ssts.iter().map(|id| snapshot.sstables[id].table_size()).sum::<u64>())
+            .collect::<Vec<_>>();
+        let mut target_level_sizes = vec![0u64; max_levels];
+        target_level_sizes[max_levels - 1] = real_level_sizes[max_levels - 1].max(base_level_size_bytes);
+        let mut base_level = max_levels;
+        for i in (0..max_levels - 1).rev() {
+            let next_level_size = target_level_sizes[i + 1];
+            if next_level_size > base_level_size_bytes {
+                target_level_sizes[i] = next_level_size / self.option.level_size_multiplier as u64;
+            }
+            if target_level_sizes[i] > 0 {
+                base_level = i + 1;
+            }
+        }
+        // when the bottom level shrinks the target sizes move down, but L0 must not skip a level
+        // that still has data, its older values would hide the newer ones compacted below it
+        if let Some(first_non_empty) = snapshot.levels.iter().position(|(_, ssts)| !ssts.is_empty()) {
+            base_level = base_level.min(first_non_empty + 1);
+        }
+
+        if snapshot.l0_sstables.len() >= self.option.level0_file_num_compaction_trigger {
+            return Some(LeveledCompactionTask {
+                upper_level: None,
+                upper_level_sst_ids: snapshot.l0_sstables.clone(),
+                lower_level: base_level,
+                lower_level_sst_ids: self.find_overlapping_ssts(snapshot, &snapshot.l0_sstables, base_level),
+                is_lower_level_bottom_level: base_level == max_levels,
+            });
+        }
+
+        // the bottom level has nowhere to go, so it is never picked
+        let mut priorities = Vec::with_capacity(max_levels);
+        for level in 0..max_levels - 1 {
+            let score = match (real_level_sizes[level], target_level_sizes[level]) {
+                (0, 0) => continue,
+                // a level without a target size is drained before anything else
+                (_, 0) => f64::INFINITY,
+                (real, target) => real as f64 / target as f64,
+            };
+            if score > 1.0 {
+                priorities.push((score, level + 1));
+            }
+        }
+        let (_, level) = priorities
+            .into_iter()
+            .max_by(|a, b| a.partial_cmp(b).unwrap())?;
+        // the oldest SsTable of the level has been there for the longest time
+        let selected_sst = *snapshot.levels[level - 1].1.iter().min().unwrap();
+        Some(LeveledCompactionTask {
+            upper_level: Some(level),
+            upper_level_sst_ids: vec![selected_sst],
+            lower_level: level + 1,
+            lower_level_sst_ids: self.find_overlapping_ssts(snapshot, &[selected_sst], level + 1),
+            is_lower_level_bottom_level: level + 1 == max_levels,
+        })
+    }
+
+    /// Swap the compacted SsTables of both levels with the output. The output SsTables must
+    /// already be in `snapshot.sstables`, they are needed to keep the lower level sorted.
+    pub fn apply_compaction_result(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &LeveledCompactionTask,
+        output: &[usize],
+    ) -> (LsmStorageState, Vec<usize>) {
+        let mut snapshot = snapshot.clone();
+        let mut files_to_remove = Vec::new();
+
+        let mut upper_level_sst_ids_set = task.upper_level_sst_ids.iter().copied().collect::<HashSet<_>>();
+        if let Some(upper_level) = task.upper_level {
+            snapshot.levels[upper_level - 1]
+                .1
+                .retain(|id| !upper_level_sst_ids_set.remove(id));
+        } else {
+            // new SsTables may have been flushed into L0 while compacting, they stay
+            snapshot
+                .l0_sstables
+                .retain(|id| !upper_level_sst_ids_set.remove(id));
+        }
+        assert!(upper_level_sst_ids_set.is_empty(), "some upper level SsTables not found");
+        files_to_remove.extend(&task.upper_level_sst_ids);
+
+        let mut lower_level_sst_ids_set = task.lower_level_sst_ids.iter().copied().collect::<HashSet<_>>();
+        let mut lower_level_ssts = std::mem::take(&mut snapshot.levels[task.lower_level - 1].1);
+        lower_level_ssts.retain(|id| !lower_level_sst_ids_set.remove(id));
+        assert!(lower_level_sst_ids_set.is_empty(), "some lower level SsTables not found");
+        files_to_remove.extend(&task.lower_level_sst_ids);
+
+        lower_level_ssts.extend(output);
+        lower_level_ssts.sort_by(|x, y| snapshot.sstables[x].first_key().cmp(snapshot.sstables[y].first_key()));
+        snapshot.levels[task.lower_level - 1].1 = lower_level_ssts;
+        (snapshot, files_to_remove)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use bytes::Bytes;
+    use super::{LeveledCompactionController, LeveledCompactionOption};
+    use crate::key::KeyBytes;
+    use crate::lsm_storage::LsmStorageState;
+    use crate::table::SsTable;
+
+    const MB: u64 = 1024 * 1024;
+
+    fn option() -> LeveledCompactionOption {
+        LeveledCompactionOption {
+            level_size_multiplier: 10,
+            base_level_size_mb: 1,
+            max_levels: 4,
+            level0_file_num_compaction_trigger: 2,
+        }
+    }
+
+    // (id, size, first key, last key) for L0 and for each of the 4 levels
+    fn state(l0: &[(usize, u64, &str, &str)], levels: [&[(usize, u64, &str, &str)]; 4]) -> LsmStorageState {
+        let mut sstables = HashMap::new();
+        let mut add = |ssts: &[(usize, u64, &str, &str)]| {
+            ssts.iter()
+                .map(|&(id, size, first, last)| {
+                    let key = |k: &str| KeyBytes::from_bytes_with_ts(Bytes::copy_from_slice(k.as_bytes()), 0);
+                    sstables.insert(id, Arc::new(SsTable::create_meta_only(id, size, key(first), key(last))));
+                    id
+                })
+                .collect::<Vec<_>>()
+        };
+        let l0_sstables = add(l0);
+        let levels = levels.iter().enumerate().map(|(i, ssts)| (i + 1, add(ssts))).collect();
+        LsmStorageState::with_sstables(l0_sstables, levels, sstables)
+    }
+
+    #[test]
+    fn l0_goes_to_the_first_level_with_a_target_size() {
+        let controller = LeveledCompactionController::new(option());
+        // with 50MB at the bottom the targets are 5MB for L3, 0.5MB for L2 and nothing for L1
+        let l2: &[_] = &[(30, MB / 10, "a", "b"), (31, MB / 10, "c", "e"), (32, MB / 10, "f", "g")];
+        let snapshot = state(
+            &[(21, MB, "x", "y"), (20, MB, "c", "d")],
+            [&[], l2, &[], &[(10, 50 * MB, "a", "z")]],
+        );
+        let task = controller.generate_compaction_task(&snapshot).unwrap();
+        assert_eq!(task.upper_level, None);
+        assert_eq!(task.upper_level_sst_ids, vec![21, 20]);
+        assert_eq!(task.lower_level, 2);
+        assert_eq!(task.lower_level_sst_ids, vec![31, 32]);
+        assert!(!task.is_lower_level_bottom_level);
+
+        // below the trigger, and no level is over its target
+        let snapshot = state(&[(20, MB, "c", "d")], [&[], l2, &[], &[(10, 50 * MB, "a", "z")]]);
+        assert!(controller.generate_compaction_task(&snapshot).is_none());
+    }
+
+    #[test]
+    fn l0_never_skips_a_level_with_data_when_the_bottom_shrinks() {
+        let controller = LeveledCompactionController::new(option());
+        // with 9MB at the bottom the target of L3 is 0.9MB and L2 gets none, but L2 still holds
+        // SsTables compacted there while the bottom level was larger
+        let l2: &[_] = &[(30, MB / 10, "a", "f")];
+        let bottom: &[_] = &[(10, 9 * MB, "a", "z")];
+        let snapshot = state(&[(21, MB, "c", "d"), (20, MB, "a", "b")], [&[], l2, &[], bottom]);
+        let task = controller.generate_compaction_task(&snapshot).unwrap();
+        assert_eq!(task.upper_level, None);
+        assert_eq!(task.lower_level, 2);
+        assert_eq!(task.lower_level_sst_ids, vec![30]);
+
+        // below the trigger, L2 is drained into L3 even though it is tiny
+        let snapshot = state(&[(20, MB, "a", "b")], [&[], l2, &[], bottom]);
+        let task = controller.generate_compaction_task(&snapshot).unwrap();
+        assert_eq!(task.upper_level, Some(2));
+        assert_eq!(task.upper_level_sst_ids, vec![30]);
+        assert_eq!(task.lower_level, 3);
+        assert!(task.lower_level_sst_ids.is_empty());
+        assert!(!task.is_lower_level_bottom_level);
+    }
+
+    #[test]
+    fn the_level_furthest_over_its_target_is_compacted() {
+        let controller = LeveledCompactionController::new(option());
+        let l2: &[_] = &[(31, MB, "g", "k"), (30, MB, "a", "f")];
+        let bottom: &[_] = &[(10, 50 * MB, "a", "z")];
+        // L2 is at 4 times its target of 0.5MB, L3 at 2 times its target of 5MB
+        let l3: &[_] = &[(40, 5 * MB, "a", "m"), (41, 5 * MB, "n", "z")];
+        let task = controller.generate_compaction_task(&state(&[], [&[], l2, l3, bottom])).unwrap();
+        assert_eq!(task.upper_level, Some(2));
+        // the oldest SsTable of the level
+        assert_eq!(task.upper_level_sst_ids, vec![30]);
+        assert_eq!(task.lower_level, 3);
+        assert_eq!(task.lower_level_sst_ids, vec![40]);
+        assert!(!task.is_lower_level_bottom_level);
+
+        // now L3 is at 6 times its target
+        let l3: &[_] = &[(40, 15 * MB, "a", "m"), (41, 15 * MB, "n", "z")];
+        let task = controller.generate_compaction_task(&state(&[], [&[], l2, l3, bottom])).unwrap();
+        assert_eq!(task.upper_level, Some(3));
+        assert_eq!(task.upper_level_sst_ids, vec![40]);
+        assert_eq!(task.lower_level, 4);
+        assert_eq!(task.lower_level_sst_ids, vec![10]);
+        assert!(task.is_lower_level_bottom_level);
+    }
+
+    #[test]
+    fn invalid_options_are_rejected() {
+        assert!(option().validate().is_ok());
+        for option in [
+            LeveledCompactionOption { max_levels: 0, ..option() },
+            LeveledCompactionOption { level_size_multiplier: 0, ..option() },
+            LeveledCompactionOption { level0_file_num_compaction_trigger: 0, ..option() },
+        ] {
+            assert!(option.validate().is_err());
+        }
+        // and an empty range overlaps with nothing instead of panicking
+        let controller = LeveledCompactionController::new(option());
+        let snapshot = state(&[], [&[(30, MB, "a", "z")], &[], &[], &[]]);
+        assert!(controller.find_overlapping_ssts(&snapshot, &[], 1).is_empty());
+    }
+}
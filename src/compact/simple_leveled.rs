@@ -0,0 +1,202 @@
+use anyhow::{ensure, Result};
+use crate::lsm_storage::LsmStorageState;
+
+#[derive(Debug, Clone)]
+pub struct SimpleLeveledCompactionOption {
+    /// The lower level is compacted with the upper level once
+    /// `lower level files / upper level files` drops below this percentage.
+    pub size_ratio_percent: usize,
+    /// L0 is compacted into L1 once it has this many SsTables.
+    pub level0_file_num_compaction_trigger: usize,
+    pub max_levels: usize,
+}
+
+impl SimpleLeveledCompactionOption {
+    pub(crate) fn validate(&self) -> Result<()> {
+        ensure!(self.max_levels >= 1, "simple leveled compaction needs at least one level");
+        ensure!(
+            self.level0_file_num_compaction_trigger >= 1,
+            "level0_file_num_compaction_trigger must be at least 1"
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SimpleLeveledCompactionTask {
+    // None means the upper level is L0
+    pub upper_level: Option<usize>,
+    pub upper_level_sst_ids: Vec<usize>,
+    pub lower_level: usize,
+    pub lower_level_sst_ids: Vec<usize>,
+    pub is_lower_level_bottom_level: bool,
+}
+
+pub struct SimpleLeveledCompactionController {
+    option: SimpleLeveledCompactionOption,
+}
+
+impl SimpleLeveledCompactionController {
+    pub fn new(option: SimpleLeveledCompactionOption) -> Self {
+        Self { option }
+    }
+
+    /// Find the first pair of adjacent levels whose size ratio is off, the sizes are measured in
+    /// the number of SsTables.
+    pub fn generate_compaction_task(&self, snapshot: &LsmStorageState) -> Option<SimpleLeveledCompactionTask> {
+        let mut level_sizes = Vec::with_capacity(self.option.max_levels + 1);
+        level_sizes.push(snapshot.l0_sstables.len());
+        for (_, files) in snapshot.levels.iter() {
+            level_sizes.push(files.len());
+        }
+
+        for upper in 0..self.option.max_levels {
+            if upper == 0 && snapshot.l0_sstables.len() < self.option.level0_file_num_compaction_trigger {
+                continue;
+            }
+            if level_sizes[upper] == 0 {
+                continue;
+            }
+            let lower = upper + 1;
+            let size_ratio = level_sizes[lower] as f64 / level_sizes[upper] as f64;
+            if size_ratio < self.option.size_ratio_percent as f64 / 100.0 {
+                return Some(SimpleLeveledCompactionTask {
+                    upper_level: if upper == 0 { None } else { Some(upper) },
+                    upper_level_sst_ids: if upper == 0 {
+                        snapshot.l0_sstables.clone()
+                    } else {
+                        snapshot.levels[upper - 1].1.clone()
+                    },
+                    lower_level: lower,
+                    lower_level_sst_ids: snapshot.levels[lower - 1].1.clone(),
+                    is_lower_level_bottom_level: lower == self.option.max_levels,
+                });
+            }
+        }
+        None
+    }
+
+    /// Put the output of the task into the state, returns the new state and the SsTables that are
+    /// not used anymore. The state might have new L0 SsTables compared with the one the task was
+    /// generated from, those must be kept.
+    pub fn apply_compaction_result(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &SimpleLeveledCompactionTask,
+        output: &[usize],
+    ) -> (LsmStorageState, Vec<usize>) {
+        let mut snapshot = snapshot.clone();
+        let mut files_to_remove = Vec::new();
+        if let Some(upper_level) = task.upper_level {
+            assert_eq!(
+                task.upper_level_sst_ids,
+                snapshot.levels[upper_level - 1].1,
+                "sst mismatched"
+            );
+            files_to_remove.extend(&snapshot.levels[upper_level - 1].1);
+            snapshot.levels[upper_level - 1].1.clear();
+        } else {
+            files_to_remove.extend(&task.upper_level_sst_ids);
+            snapshot
+                .l0_sstables
+                .retain(|id| !task.upper_level_sst_ids.contains(id));
+        }
+        assert_eq!(
+            task.lower_level_sst_ids,
+            snapshot.levels[task.lower_level - 1].1,
+            "sst mismatched"
+        );
+        files_to_remove.extend(&snapshot.levels[task.lower_level - 1].1);
+        snapshot.levels[task.lower_level - 1].1 = output.to_vec();
+        (snapshot, files_to_remove)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::{SimpleLeveledCompactionController, SimpleLeveledCompactionOption};
+    use crate::lsm_storage::LsmStorageState;
+
+    fn option() -> SimpleLeveledCompactionOption {
+        SimpleLeveledCompactionOption {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+        }
+    }
+
+    // the number of SsTables in L0 and in each of the 3 levels, the controller only counts them
+    fn state(l0_size: usize, level_sizes: [usize; 3]) -> LsmStorageState {
+        let mut next_id = 0;
+        let mut ssts = |size: usize| {
+            next_id += size;
+            (next_id - size..next_id).collect::<Vec<_>>()
+        };
+        let l0_sstables = ssts(l0_size);
+        let levels = level_sizes.iter().enumerate().map(|(i, &size)| (i + 1, ssts(size))).collect();
+        LsmStorageState::with_sstables(l0_sstables, levels, HashMap::new())
+    }
+
+    #[test]
+    fn l0_is_compacted_once_it_reaches_the_trigger() {
+        let controller = SimpleLeveledCompactionController::new(option());
+        assert!(controller.generate_compaction_task(&state(1, [0, 0, 0])).is_none());
+
+        let snapshot = state(2, [1, 4, 8]);
+        let task = controller.generate_compaction_task(&snapshot).unwrap();
+        assert_eq!(task.upper_level, None);
+        assert_eq!(task.upper_level_sst_ids, snapshot.l0_sstables);
+        assert_eq!(task.lower_level, 1);
+        assert_eq!(task.lower_level_sst_ids, snapshot.levels[0].1);
+        assert!(!task.is_lower_level_bottom_level);
+
+        // a state that grew new L0 SsTables while compacting keeps them
+        let mut latest = snapshot.clone();
+        latest.l0_sstables.insert(0, 100);
+        let (latest, files_to_remove) = controller.apply_compaction_result(&latest, &task, &[200, 201]);
+        assert_eq!(latest.l0_sstables, vec![100]);
+        assert_eq!(latest.levels[0].1, vec![200, 201]);
+        assert_eq!(latest.levels[1..], snapshot.levels[1..]);
+        let mut compacted = snapshot.l0_sstables.clone();
+        compacted.extend(&snapshot.levels[0].1);
+        assert_eq!(files_to_remove, compacted);
+    }
+
+    #[test]
+    fn the_first_pair_of_levels_with_an_off_ratio_is_compacted() {
+        let controller = SimpleLeveledCompactionController::new(option());
+        // L2 is not 200% of L1, and L3 is not 200% of L2 either, the upper pair goes first
+        let snapshot = state(0, [2, 3, 4]);
+        let task = controller.generate_compaction_task(&snapshot).unwrap();
+        assert_eq!(task.upper_level, Some(1));
+        assert_eq!(task.upper_level_sst_ids, snapshot.levels[0].1);
+        assert_eq!(task.lower_level, 2);
+        assert_eq!(task.lower_level_sst_ids, snapshot.levels[1].1);
+        assert!(!task.is_lower_level_bottom_level);
+        let (latest, files_to_remove) = controller.apply_compaction_result(&snapshot, &task, &[100]);
+        assert!(latest.levels[0].1.is_empty());
+        assert_eq!(latest.levels[1].1, vec![100]);
+        assert_eq!(files_to_remove.len(), 5);
+
+        let task = controller.generate_compaction_task(&state(0, [1, 2, 3])).unwrap();
+        assert_eq!(task.upper_level, Some(2));
+        assert_eq!(task.lower_level, 3);
+        assert!(task.is_lower_level_bottom_level);
+
+        // every ratio is fine, and an empty level never counts as an upper level
+        assert!(controller.generate_compaction_task(&state(1, [1, 2, 4])).is_none());
+        assert!(controller.generate_compaction_task(&state(0, [0, 1, 2])).is_none());
+    }
+
+    #[test]
+    fn invalid_options_are_rejected() {
+        assert!(option().validate().is_ok());
+        for option in [
+            SimpleLeveledCompactionOption { max_levels: 0, ..option() },
+            SimpleLeveledCompactionOption { level0_file_num_compaction_trigger: 0, ..option() },
+        ] {
+            assert!(option.validate().is_err());
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use anyhow::{ensure, Result};
+use crate::lsm_storage::LsmStorageState;
+
+#[derive(Debug, Clone)]
+pub struct TieredCompactionOption {
+    /// Compaction only starts once there are more sorted runs than this.
+    pub num_tiers: usize,
+    /// Everything is merged into one sorted run once the sorted runs above the bottom one are
+    /// this many percent of the bottom one.
+    pub max_size_amplification_percent: usize,
+    /// A sorted run joins the runs above it when it is at most this many percent larger than
+    /// all of them together.
+    pub size_ratio: usize,
+    /// The minimum number of sorted runs merged by a size ratio compaction.
+    pub min_merge_width: usize,
+}
+
+impl TieredCompactionOption {
+    pub(crate) fn validate(&self) -> Result<()> {
+        ensure!(self.num_tiers >= 1, "tiered compaction needs at least one tier");
+        ensure!(
+            self.max_size_amplification_percent >= 1,
+            "max_size_amplification_percent must be at least 1"
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TieredCompactionTask {
+    // (tier id, the SsTable ids of the tier), from the newest tier to the oldest
+    pub tiers: Vec<(usize, Vec<usize>)>,
+    pub bottom_tier_included: bool,
+}
+
+pub struct TieredCompactionController {
+    option: TieredCompactionOption,
+}
+
+impl TieredCompactionController {
+    pub fn new(option: TieredCompactionOption) -> Self {
+        Self { option }
+    }
+
+    /// Every flush adds a new tier to the front of `levels`, the sizes of the tiers are measured
+    /// in the number of SsTables.
+    pub fn generate_compaction_task(&self, snapshot: &LsmStorageState) -> Option<TieredCompactionTask> {
+        assert!(snapshot.l0_sstables.is_empty(), "tiered compaction does not use L0");
+        if snapshot.levels.len() <= self.option.num_tiers {
+            return None;
+        }
+
+        // the space amplification is what the tiers above the bottom tier add on top of it
+        let upper_size = snapshot.levels[..snapshot.levels.len() - 1]
+            .iter()
+            .map(|(_, ssts)| ssts.len())
+            .sum::<usize>();
+        let bottom_size = snapshot.levels.last().unwrap().1.len();
+        if upper_size as f64 * 100.0 >= self.option.max_size_amplification_percent as f64 * bottom_size as f64 {
+            return Some(TieredCompactionTask {
+                tiers: snapshot.levels.clone(),
+                bottom_tier_included: true,
+            });
+        }
+
+        // starting from the newest tier, keep taking the next tier as long as it is not much
+        // larger than everything taken so far
+        let size_ratio_trigger = (100.0 + self.option.size_ratio as f64) / 100.0;
+        let mut size = snapshot.levels[0].1.len();
+        let mut num_tiers_to_take = 1;
+        while num_tiers_to_take < snapshot.levels.len() {
+            let next_tier_size = snapshot.levels[num_tiers_to_take].1.len();
+            if next_tier_size as f64 > size as f64 * size_ratio_trigger {
+                break;
+            }
+            size += next_tier_size;
+            num_tiers_to_take += 1;
+        }
+        if num_tiers_to_take >= self.option.min_merge_width.max(2) {
+            return Some(TieredCompactionTask {
+                tiers: snapshot.levels[..num_tiers_to_take].to_vec(),
+                bottom_tier_included: num_tiers_to_take == snapshot.levels.len(),
+            });
+        }
+
+        // the tiers are too different in size to merge by ratio, merge the newest ones so that
+        // the number of tiers goes below the limit again
+        let num_tiers_to_take = snapshot.levels.len() - self.option.num_tiers + 2;
+        let num_tiers_to_take = num_tiers_to_take.min(snapshot.levels.len());
+        Some(TieredCompactionTask {
+            tiers: snapshot.levels[..num_tiers_to_take].to_vec(),
+            bottom_tier_included: num_tiers_to_take == snapshot.levels.len(),
+        })
+    }
+
+    /// Replace the compacted tiers with one tier made of the output. New tiers may have been
+    /// flushed in front of them while compacting, so they are looked up by their ids.
+    pub fn apply_compaction_result(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &TieredCompactionTask,
+        output: &[usize],
+    ) -> (LsmStorageState, Vec<usize>) {
+        let mut snapshot = snapshot.clone();
+        let mut tiers_to_remove = task
+            .tiers
+            .iter()
+            .map(|(id, ssts)| (*id, ssts))
+            .collect::<HashMap<_, _>>();
+        let mut levels = Vec::with_capacity(snapshot.levels.len());
+        let mut new_tier_added = false;
+        let mut files_to_remove = Vec::new();
+        for (tier_id, ssts) in snapshot.levels.iter() {
+            if let Some(task_ssts) = tiers_to_remove.remove(tier_id) {
+                assert_eq!(task_ssts, ssts, "file changed after issuing compaction task");
+                files_to_remove.extend(ssts.iter().copied());
+                // the compacted tiers are next to each other, the new tier takes their place
+                if !new_tier_added {
+                    new_tier_added = true;
+                    if !output.is_empty() {
+                        levels.push((output[0], output.to_vec()));
+                    }
+                }
+            } else {
+                levels.push((*tier_id, ssts.clone()));
+            }
+        }
+        assert!(tiers_to_remove.is_empty(), "some tiers not found");
+        snapshot.levels = levels;
+        (snapshot, files_to_remove)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::{TieredCompactionController, TieredCompactionOption};
+    use crate::lsm_storage::LsmStorageState;
+
+    fn option() -> TieredCompactionOption {
+        TieredCompactionOption {
+            num_tiers: 3,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+        }
+    }
+
+    fn controller() -> TieredCompactionController {
+        TieredCompactionController::new(option())
+    }
+
+    // the size of each tier from the newest to the oldest, in SsTables, the controller never
+    // looks into the SsTables themselves
+    fn state(tier_sizes: &[usize]) -> LsmStorageState {
+        let mut next_id = 0;
+        let levels = tier_sizes
+            .iter()
+            .map(|&size| {
+                let ssts = (next_id..next_id + size).collect::<Vec<_>>();
+                next_id += size;
+                (ssts[0], ssts)
+            })
+            .collect();
+        LsmStorageState::with_sstables(Vec::new(), levels, HashMap::new())
+    }
+
+    fn compacted_tiers(tier_sizes: &[usize]) -> Option<(Vec<usize>, bool)> {
+        let task = controller().generate_compaction_task(&state(tier_sizes))?;
+        Some((task.tiers.iter().map(|(_, ssts)| ssts.len()).collect(), task.bottom_tier_included))
+    }
+
+    #[test]
+    fn nothing_to_do_within_the_number_of_tiers() {
+        assert_eq!(compacted_tiers(&[1, 1, 1]), None);
+    }
+
+    #[test]
+    fn space_amplification_merges_every_tier() {
+        // 3 SsTables above a bottom tier of 1 is 300%
+        assert_eq!(compacted_tiers(&[1, 1, 1, 1]), Some((vec![1, 1, 1, 1], true)));
+    }
+
+    #[test]
+    fn size_ratio_merges_the_newest_similar_tiers() {
+        // the tier of 10 is far larger than the 4 SsTables above it, merging to get under the
+        // number of tiers would take 5 tiers
+        assert_eq!(compacted_tiers(&[1, 1, 1, 1, 10, 100]), Some((vec![1, 1, 1, 1], false)));
+        assert_eq!(compacted_tiers(&[1, 1, 2, 40, 41, 1000]), Some((vec![1, 1, 2], false)));
+    }
+
+    #[test]
+    fn too_many_tiers_merges_the_newest_ones() {
+        // every tier is 3 times the one above it, so the ratio never allows a merge
+        assert_eq!(compacted_tiers(&[1, 3, 9, 27, 81]), Some((vec![1, 3, 9, 27], false)));
+    }
+
+    #[test]
+    fn invalid_options_are_rejected() {
+        assert!(option().validate().is_ok());
+        for option in [
+            TieredCompactionOption { num_tiers: 0, ..option() },
+            TieredCompactionOption { max_size_amplification_percent: 0, ..option() },
+        ] {
+            assert!(option.validate().is_err());
+        }
+    }
+}
@@ -1,13 +1,16 @@
-pub(crate) mod merge_iterator;
-mod two_merge_iterator;
+pub mod merge_iterator;
+pub mod two_merge_iterator;
+pub mod concat_iterator;
 
 pub trait StorageIterator {
-    // type KeyType<'a>: PartialEq + Eq + PartialOrd + Ord where Self: 'a;
+    // the iterators inside the engine work on timestamped keys, the ones handed to users only
+    // show the user key
+    type KeyType<'a>: PartialEq + Eq + PartialOrd + Ord where Self: 'a;
     fn next(&mut self) -> anyhow::Result<()>;
-    fn key(&self) -> &[u8];
+    fn key(&self) -> Self::KeyType<'_>;
     fn value(&self) -> &[u8];
     fn is_valid(&self) -> bool;
     fn num_active_iterators(&self) -> usize {
         1
     }
-}
\ No newline at end of file
+}
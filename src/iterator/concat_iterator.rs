@@ -0,0 +1,90 @@
+use std::sync::Arc;
+use anyhow::Result;
+use crate::iterator::StorageIterator;
+use crate::key::KeySlice;
+use crate::table::{SsTable, SsTableIterator};
+
+/// Iterates over SsTables that are sorted and do not overlap with each other (the SsTables of
+/// one level), so only one SsTable is opened at a time instead of merging all of them.
+pub struct SstConcatIterator {
+    current: Option<SsTableIterator>,
+    // the index of the SsTable that comes after the current one
+    next_sst_idx: usize,
+    sstables: Vec<Arc<SsTable>>,
+}
+
+impl SstConcatIterator {
+    pub fn create_and_seek_to_first(sstables: Vec<Arc<SsTable>>) -> Result<Self> {
+        if sstables.is_empty() {
+            return Ok(Self {
+                current: None,
+                next_sst_idx: 0,
+                sstables,
+            });
+        }
+        let mut iter = Self {
+            current: Some(SsTableIterator::create_and_seek_to_first(sstables[0].clone())?),
+            next_sst_idx: 1,
+            sstables,
+        };
+        iter.move_until_valid()?;
+        Ok(iter)
+    }
+
+    pub fn create_and_seek_to_key(sstables: Vec<Arc<SsTable>>, key: KeySlice) -> Result<Self> {
+        // the first SsTable whose last key is not smaller than the key is the one to start from
+        let idx = sstables.partition_point(|table| table.last_key().as_key_slice() < key);
+        if idx >= sstables.len() {
+            return Ok(Self {
+                current: None,
+                next_sst_idx: sstables.len(),
+                sstables,
+            });
+        }
+        let mut iter = Self {
+            current: Some(SsTableIterator::create_and_seek_to_key(sstables[idx].clone(), key)?),
+            next_sst_idx: idx + 1,
+            sstables,
+        };
+        iter.move_until_valid()?;
+        Ok(iter)
+    }
+
+    fn move_until_valid(&mut self) -> Result<()> {
+        while let Some(iter) = self.current.as_mut() {
+            if iter.is_valid() {
+                break;
+            }
+            if self.next_sst_idx >= self.sstables.len() {
+                self.current = None;
+            } else {
+                self.current = Some(SsTableIterator::create_and_seek_to_first(
+                    self.sstables[self.next_sst_idx].clone(),
+                )?);
+                self.next_sst_idx += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageIterator for SstConcatIterator {
+    type KeyType<'a> = KeySlice<'a>;
+
+    fn next(&mut self) -> Result<()> {
+        self.current.as_mut().unwrap().next()?;
+        self.move_until_valid()
+    }
+
+    fn key(&self) -> KeySlice<'_> {
+        self.current.as_ref().unwrap().key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.current.as_ref().unwrap().value()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.current.as_ref().is_some_and(|iter| iter.is_valid())
+    }
+}
@@ -18,20 +18,20 @@ impl<T: StorageIterator> Eq for HeapWrapper<T> {}
 
 impl<T: StorageIterator> PartialOrd for HeapWrapper<T> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        match self.1.key().cmp(other.1.key()) {
-            Ordering::Less => Some(Ordering::Less),
-            Ordering::Greater => Some(Ordering::Greater),
-            Ordering::Equal => self.0.partial_cmp(&other.0)
-        // we use x.reverse() right here since the default behaviour of the BinaryHeap is
-        // the greater iterator will be on top, now we reverse it so the less iterator
-        // is on top
-        }.map(|x| x.reverse())
+        Some(self.cmp(other))
     }
 }
 
 impl<T: StorageIterator> Ord for HeapWrapper<T> {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        match self.1.key().cmp(&other.1.key()) {
+            Ordering::Less => Ordering::Less,
+            Ordering::Greater => Ordering::Greater,
+            Ordering::Equal => self.0.cmp(&other.0)
+        // we use x.reverse() right here since the default behaviour of the BinaryHeap is
+        // the greater iterator will be on top, now we reverse it so the less iterator
+        // is on top
+        }.reverse()
     }
 }
 
@@ -74,6 +74,8 @@ impl<T: StorageIterator> MergeIterator<T> {
 }
 
 impl<T: StorageIterator> StorageIterator for MergeIterator<T> {
+    type KeyType<'a> = T::KeyType<'a> where Self: 'a;
+
     // the right way to think about this is just:
     // keys in iter1: a, c, d;
     // keys in iter2: a, b, c;
@@ -126,10 +128,18 @@ impl<T: StorageIterator> StorageIterator for MergeIterator<T> {
             return Ok(());
         }
 
+        // the current iterator may have moved past the one on top of the heap, in that case they
+        // have to be swapped, otherwise keys from the other iterators are skipped
+        if let Some(mut inner_iter) = self.iters.peek_mut()
+            && *current < *inner_iter
+        {
+            std::mem::swap(&mut *inner_iter, current);
+        }
+
         Ok(())
     }
 
-    fn key(&self) -> &[u8] {
+    fn key(&self) -> T::KeyType<'_> {
         self.current.as_ref().unwrap().1.key()
     }
 
@@ -143,4 +153,41 @@ impl<T: StorageIterator> StorageIterator for MergeIterator<T> {
             .map(|x| x.1.is_valid())
             .unwrap_or(false)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+    use anyhow::Result;
+    use super::MergeIterator;
+    use crate::iterator::StorageIterator;
+    use crate::key::KeySlice;
+    use crate::mem_table::MemTable;
+
+    #[test]
+    fn current_iterator_moving_past_the_heap_is_swapped() -> Result<()> {
+        // the first iterator starts in front, and its next key is past the key of the second one,
+        // both write at the same timestamp so that "c" is the same key in both
+        let key = |key: &'static [u8]| KeySlice::from_slice(key, 1);
+        let memtable = |id, keys: [&'static [u8]; 3], value: &'static [u8]| -> Result<MemTable> {
+            let memtable = MemTable::create(id);
+            memtable.put_batch(&keys.map(|k| (key(k), value)))?;
+            Ok(memtable)
+        };
+        let newer = memtable(1, [b"a", b"c", b"e"], b"newer")?;
+        let older = memtable(0, [b"b", b"c", b"d"], b"older")?;
+        let iters = [&newer, &older]
+            .into_iter()
+            .map(|memtable| Box::new(memtable.scan(Bound::Unbounded, Bound::Unbounded)))
+            .collect();
+        let mut iter = MergeIterator::create(iters);
+        let mut entries = Vec::new();
+        while iter.is_valid() {
+            entries.push((iter.key().key_ref().to_vec(), iter.value().to_vec()));
+            iter.next()?;
+        }
+        let expected = [("a", "newer"), ("b", "older"), ("c", "newer"), ("d", "older"), ("e", "newer")]
+            .map(|(key, value)| (key.as_bytes().to_vec(), value.as_bytes().to_vec()));
+        assert_eq!(entries, expected);
+        Ok(())
+    }
+}
@@ -6,7 +6,10 @@ pub struct TwoMergeIterator<A: StorageIterator, B: StorageIterator> {
     choose_a: bool,
 }
 
-impl<A: StorageIterator, B: StorageIterator> TwoMergeIterator<A, B> {
+// both sides have to produce the same kind of keys to be compared with each other
+impl<A: 'static + StorageIterator, B: 'static + for<'a> StorageIterator<KeyType<'a> = A::KeyType<'a>>>
+    TwoMergeIterator<A, B>
+{
     fn choose_a(a: &A, b: &B) -> bool {
         if !a.is_valid() {
             return false;
@@ -18,10 +21,8 @@ impl<A: StorageIterator, B: StorageIterator> TwoMergeIterator<A, B> {
     }
 
     fn skip_b(&mut self) -> Result<()> {
-        if self.a.is_valid() {
-            if self.b.is_valid() && self.b.key() == self.a.key() {
-                self.b.next()?;
-            }
+        if self.a.is_valid() && self.b.is_valid() && self.b.key() == self.a.key() {
+            self.b.next()?;
         }
         Ok(())
     }
@@ -38,8 +39,12 @@ impl<A: StorageIterator, B: StorageIterator> TwoMergeIterator<A, B> {
     }
 }
 
-impl<A: StorageIterator, B: StorageIterator> StorageIterator for TwoMergeIterator<A, B> {
-    fn key(&self) -> &[u8] {
+impl<A: 'static + StorageIterator, B: 'static + for<'a> StorageIterator<KeyType<'a> = A::KeyType<'a>>>
+    StorageIterator for TwoMergeIterator<A, B>
+{
+    type KeyType<'a> = A::KeyType<'a> where Self: 'a;
+
+    fn key(&self) -> A::KeyType<'_> {
         if self.choose_a {
             self.a.key()
         } else {
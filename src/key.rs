@@ -0,0 +1,121 @@
+use std::cmp::Ordering;
+use std::fmt::Debug;
+use bytes::Bytes;
+
+/// A user key together with the commit timestamp of the write that produced it.
+///
+/// Keys are ordered by the user key first and then by the timestamp from the newest to the
+/// oldest, so seeking to `(key, read_ts)` lands on the newest version a reader at `read_ts` is
+/// allowed to see.
+#[derive(Clone, Copy, Default, Hash, PartialEq, Eq)]
+pub struct Key<T: AsRef<[u8]>>(T, u64);
+
+pub type KeySlice<'a> = Key<&'a [u8]>;
+pub type KeyVec = Key<Vec<u8>>;
+pub type KeyBytes = Key<Bytes>;
+
+/// The timestamp of every key written before keys were versioned.
+pub const TS_DEFAULT: u64 = 0;
+/// `(key, TS_RANGE_BEGIN)` is ordered before every version of `key`.
+pub const TS_RANGE_BEGIN: u64 = u64::MAX;
+/// `(key, TS_RANGE_END)` is ordered after every version of `key`.
+pub const TS_RANGE_END: u64 = u64::MIN;
+
+impl<T: AsRef<[u8]>> Key<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+
+    pub fn key_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+
+    pub fn key_len(&self) -> usize {
+        self.0.as_ref().len()
+    }
+
+    pub fn ts(&self) -> u64 {
+        self.1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.as_ref().is_empty()
+    }
+
+    pub fn as_key_slice(&self) -> KeySlice<'_> {
+        Key(self.0.as_ref(), self.1)
+    }
+}
+
+impl<'a> KeySlice<'a> {
+    pub fn from_slice(key: &'a [u8], ts: u64) -> Self {
+        Self(key, ts)
+    }
+
+    pub fn to_key_vec(self) -> KeyVec {
+        Key(self.0.to_vec(), self.1)
+    }
+
+    pub fn to_key_bytes(self) -> KeyBytes {
+        Key(Bytes::copy_from_slice(self.0), self.1)
+    }
+}
+
+impl KeyVec {
+    pub fn new() -> Self {
+        Self(Vec::new(), TS_DEFAULT)
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear()
+    }
+
+    /// Replace the content with `key` while reusing the allocation.
+    pub fn set_from_slice(&mut self, key: KeySlice) {
+        self.0.clear();
+        self.0.extend_from_slice(key.0);
+        self.1 = key.1;
+    }
+
+    pub fn into_key_bytes(self) -> KeyBytes {
+        Key(self.0.into(), self.1)
+    }
+
+    // the block iterator rebuilds a key from the prefix it shares with the previous key, so it
+    // needs to edit the user key and the timestamp separately
+    pub(crate) fn key_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.0
+    }
+
+    pub(crate) fn set_ts(&mut self, ts: u64) {
+        self.1 = ts
+    }
+}
+
+impl KeyBytes {
+    pub fn from_bytes_with_ts(key: Bytes, ts: u64) -> Self {
+        Self(key, ts)
+    }
+}
+
+impl<T: AsRef<[u8]> + Debug> Debug for Key<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}@{}", self.0, self.1)
+    }
+}
+
+impl<T: AsRef<[u8]> + Eq> PartialOrd for Key<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: AsRef<[u8]> + Eq> Ord for Key<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0
+            .as_ref()
+            .cmp(other.0.as_ref())
+            // the newer version comes first
+            .then_with(|| other.1.cmp(&self.1))
+    }
+}
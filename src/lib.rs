@@ -1,10 +1,11 @@
-mod mem_table;
-mod lsm_storage;
-mod table;
-mod compact;
-mod block;
-mod manifest;
-mod mvcc;
-mod wal;
-mod iterator;
-mod lsm_iterator;
\ No newline at end of file
+pub mod mem_table;
+pub mod lsm_storage;
+pub mod table;
+pub mod compact;
+pub mod block;
+pub mod manifest;
+pub mod mvcc;
+pub mod wal;
+pub mod iterator;
+pub mod key;
+pub mod lsm_iterator;
@@ -1,47 +1,116 @@
+use std::ops::Bound;
+use bytes::Bytes;
+use crate::iterator::concat_iterator::SstConcatIterator;
 use crate::iterator::merge_iterator::MergeIterator;
+use crate::iterator::two_merge_iterator::TwoMergeIterator;
 use crate::mem_table::MemTableIterator;
 use anyhow::{bail, Result};
 use crate::iterator::StorageIterator;
+use crate::table::SsTableIterator;
 
-type LsmIteratorInner = MergeIterator<MemTableIterator>;
+// memtables are newer than L0, and L0 is newer than every level, so on the same key the iterator
+// on the left always wins
+type LsmIteratorInner = TwoMergeIterator<
+    TwoMergeIterator<MergeIterator<MemTableIterator>, MergeIterator<SsTableIterator>>,
+    MergeIterator<SstConcatIterator>,
+>;
 
 pub struct LsmIterator {
     inner: LsmIteratorInner,
+    // the SsTable iterators only know where to start, so the end of the range is checked here
+    end_bound: Bound<Bytes>,
+    is_valid: bool,
+    // versions newer than this are invisible to the reader
+    read_ts: u64,
+    // the user key the iterator is on, every other version of it has to be skipped
+    prev_key: Vec<u8>,
 }
 
 impl LsmIterator {
-    pub(crate) fn new(iter: LsmIteratorInner) -> Result<Self> {
-        let mut iter = Self { inner: iter };
-        iter.move_to_non_delete()?;
+    pub(crate) fn new(iter: LsmIteratorInner, end_bound: Bound<Bytes>, read_ts: u64) -> Result<Self> {
+        let mut iter = Self {
+            is_valid: iter.is_valid(),
+            inner: iter,
+            end_bound,
+            read_ts,
+            prev_key: Vec::new(),
+        };
+        iter.move_to_key()?;
         Ok(iter)
     }
 }
 
 impl LsmIterator {
-    fn move_to_non_delete(&mut self) -> Result<()> {
-        while self.is_valid() && self.inner.value().is_empty() {
-            self.inner.next()?;
+    fn check_end_bound(&mut self) {
+        if !self.inner.is_valid() {
+            self.is_valid = false;
+            return;
         }
+        self.is_valid = match &self.end_bound {
+            Bound::Unbounded => true,
+            Bound::Included(key) => self.inner.key().key_ref() <= key.as_ref(),
+            Bound::Excluded(key) => self.inner.key().key_ref() < key.as_ref(),
+        };
+    }
+
+    fn next_inner(&mut self) -> Result<()> {
+        self.inner.next()?;
+        self.check_end_bound();
         Ok(())
     }
+
+    // The inner iterator yields every version of every key, newest version first. Move to the
+    // next user key whose newest version visible at read_ts is not a tombstone, skipping the
+    // versions of the key the iterator was on.
+    fn move_to_key(&mut self) -> Result<()> {
+        self.check_end_bound();
+        loop {
+            while self.is_valid && self.inner.key().key_ref() == self.prev_key {
+                self.next_inner()?;
+            }
+            if !self.is_valid {
+                return Ok(());
+            }
+            self.prev_key.clear();
+            self.prev_key.extend_from_slice(self.inner.key().key_ref());
+            while self.is_valid
+                && self.inner.key().key_ref() == self.prev_key
+                && self.inner.key().ts() > self.read_ts
+            {
+                self.next_inner()?;
+            }
+            if !self.is_valid {
+                return Ok(());
+            }
+            // every version of this key is newer than read_ts, so it does not exist yet
+            if self.inner.key().key_ref() != self.prev_key {
+                continue;
+            }
+            if !self.inner.value().is_empty() {
+                return Ok(());
+            }
+        }
+    }
 }
 
 impl StorageIterator for LsmIterator {
+    type KeyType<'a> = &'a [u8];
+
     fn next(&mut self) -> Result<()> {
-        self.inner.next()?;
-        self.move_to_non_delete()?;
+        self.next_inner()?;
+        self.move_to_key()?;
         Ok(())
     }
 
     fn key(&self) -> &[u8] {
-        self.inner.key()
+        self.inner.key().into_inner()
     }
 
     fn value(&self) -> &[u8] {
         self.inner.value()
     }
     fn is_valid(&self) -> bool {
-        self.inner.is_valid()
+        self.is_valid
     }
 }
 
@@ -60,21 +129,21 @@ impl<I: StorageIterator> FusedIterator<I> {
 }
 
 impl<I: StorageIterator> StorageIterator for FusedIterator<I> {
+    type KeyType<'a> = I::KeyType<'a> where Self: 'a;
+
     fn next(&mut self) -> Result<()> {
         // only move when the iterator is valid and not errored
         if self.has_errored {
             bail!("the iterator is tainted");
         }
-        if self.iter.is_valid() {
-            if let Err(e) = self.iter.next() {
-                self.has_errored = true;
-                return Err(e);
-            }
+        if self.iter.is_valid() && let Err(e) = self.iter.next() {
+            self.has_errored = true;
+            return Err(e);
         }
         Ok(())
     }
 
-    fn key(&self) -> &[u8] {
+    fn key(&self) -> I::KeyType<'_> {
         if self.has_errored || !self.iter.is_valid() {
             panic!("invalid access to the underlying iterator");
         }
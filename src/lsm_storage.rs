@@ -1,19 +1,28 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::ops::Bound;
-use std::path::PathBuf;
-use anyhow::{Result};
+use std::path::{Path, PathBuf};
+use anyhow::{ensure, Context, Result};
 use std::sync::{Arc};
-use parking_lot::{RwLock, Mutex};
+use parking_lot::{RwLock, Mutex, MutexGuard};
 use std::sync::atomic::AtomicUsize;
+use std::thread::JoinHandle;
+use std::time::Duration;
 use bytes::Bytes;
+use crossbeam_channel::{Receiver, Sender};
 use crate::block::Block;
-use crate::compact::{CompactionController, CompactionOption};
+use crate::compact::{CompactionController, CompactionOption, LeveledCompactionOption, SimpleLeveledCompactionOption};
+use crate::iterator::concat_iterator::SstConcatIterator;
 use crate::iterator::merge_iterator::MergeIterator;
+use crate::iterator::two_merge_iterator::TwoMergeIterator;
+use crate::iterator::StorageIterator;
 use crate::lsm_iterator::{FusedIterator, LsmIterator};
-use crate::manifest::Manifest;
-use crate::mem_table::MemTable;
-use crate::mvcc::LsmMvccInner;
-use crate::table::SsTable;
+use crate::manifest::{Manifest, ManifestRecord};
+use crate::mem_table::{map_bound, MemTable};
+use crate::key::{KeySlice, TS_DEFAULT, TS_RANGE_BEGIN, TS_RANGE_END};
+use crate::mvcc::{LockManager, LsmMvccInner, Snapshot, Transaction};
+use crate::table::compress::CompressionType;
+use crate::table::{FileObject, SsTable, SsTableBuilder, SsTableIterator};
 
 pub type BlockCache = moka::sync::Cache<(usize, usize), Arc<Block>>;
 
@@ -21,91 +30,471 @@ pub type BlockCache = moka::sync::Cache<(usize, usize), Arc<Block>>;
 pub struct LsmStorageState {
     // I use Arc here since it can offer fast read by just cloning it without occupy the RwLock
     // for a long time, optimizing the concurrency efficiency.
-    memtable: Arc<MemTable>,
-    immut_memtable: Vec<Arc<MemTable>>,
-    l0_sstables: Vec<usize>,
-    levels: Vec<(usize, Vec<usize>)>,
-    sstables: HashMap<usize, Arc<SsTable>>
+    pub memtable: Arc<MemTable>,
+    // the newest immutable memtable comes first
+    pub immut_memtable: Vec<Arc<MemTable>>,
+    // the newest SsTable id comes first
+    pub l0_sstables: Vec<usize>,
+    // (level, the SsTable ids in that level sorted by their key range)
+    pub levels: Vec<(usize, Vec<usize>)>,
+    pub sstables: HashMap<usize, Arc<SsTable>>
+}
+
+impl LsmStorageState {
+    fn create(config: &LsmStorageConfig) -> Self {
+        let levels = match &config.compaction_option {
+            // without compaction everything flushed just stays in L0, the single level below is
+            // only kept so that the shape of the state is the same for every option
+            CompactionOption::NoCompaction => vec![(1, Vec::new())],
+            CompactionOption::Simple(SimpleLeveledCompactionOption { max_levels, .. })
+            | CompactionOption::Leveled(LeveledCompactionOption { max_levels, .. }) => {
+                (1..=*max_levels).map(|level| (level, Vec::new())).collect()
+            }
+            // the tiers are created by flushes
+            CompactionOption::Tiered(_) => Vec::new(),
+        };
+        Self {
+            memtable: Arc::new(MemTable::create(0)),
+            immut_memtable: Vec::new(),
+            l0_sstables: Vec::new(),
+            levels,
+            sstables: HashMap::new(),
+        }
+    }
+
+    /// Rearrange the SsTables recovered from the manifest into the shape the configured
+    /// compaction strategy expects, they may have been written with another one. Both a level
+    /// and a tier are a sorted run, and `levels` keeps them from the newest to the oldest, so
+    /// they only move around as a whole and nothing has to be rewritten.
+    fn fit_levels(&mut self, option: &CompactionOption) {
+        let runs = |levels: Vec<(usize, Vec<usize>)>| {
+            levels.into_iter().map(|(_, ssts)| ssts).filter(|ssts| !ssts.is_empty())
+        };
+        let max_levels = match option {
+            CompactionOption::NoCompaction => 1,
+            CompactionOption::Simple(SimpleLeveledCompactionOption { max_levels, .. })
+            | CompactionOption::Leveled(LeveledCompactionOption { max_levels, .. }) => *max_levels,
+            CompactionOption::Tiered(_) => {
+                // every L0 SsTable is newer than the runs and becomes a tier of its own, a tier is
+                // named after its first SsTable like the ones flushed or compacted
+                let l0_tiers = std::mem::take(&mut self.l0_sstables).into_iter().map(|id| vec![id]);
+                let tiers = l0_tiers.chain(runs(std::mem::take(&mut self.levels)));
+                self.levels = tiers.map(|ssts| (ssts[0], ssts)).collect();
+                return;
+            }
+        };
+        if self.levels.iter().map(|(level, _)| *level).eq(1..=max_levels) {
+            return;
+        }
+        let mut runs = runs(std::mem::take(&mut self.levels)).collect::<Vec<_>>().into_iter();
+        // the runs that do not fit are the newest ones, they go below everything in L0, where
+        // SsTables may overlap, and the compaction merges them down from there
+        let num_extra_runs = runs.len().saturating_sub(max_levels);
+        for ssts in runs.by_ref().take(num_extra_runs) {
+            self.l0_sstables.extend(ssts);
+        }
+        // the rest fill the bottom levels
+        let num_empty_levels = max_levels - runs.len();
+        self.levels = (1..=max_levels)
+            .map(|level| (level, if level <= num_empty_levels { Vec::new() } else { runs.next().unwrap() }))
+            .collect();
+    }
+
+    /// Put a freshly flushed SsTable where the compaction strategy expects it.
+    fn apply_flush(&mut self, compaction_controller: &CompactionController, sst_id: usize) {
+        if compaction_controller.flush_to_l0() {
+            self.l0_sstables.insert(0, sst_id);
+        } else {
+            self.levels.insert(0, (sst_id, vec![sst_id]));
+        }
+    }
+}
+
+#[cfg(test)]
+impl LsmStorageState {
+    /// A state with nothing but SsTables, which is all the compaction controllers look at.
+    pub(crate) fn with_sstables(
+        l0_sstables: Vec<usize>,
+        levels: Vec<(usize, Vec<usize>)>,
+        sstables: HashMap<usize, Arc<SsTable>>,
+    ) -> Self {
+        Self {
+            memtable: Arc::new(MemTable::create(0)),
+            immut_memtable: Vec::new(),
+            l0_sstables,
+            levels,
+            sstables,
+        }
+    }
 }
 
 pub struct LsmStorageConfig {
     // a SsTable is consist of a lot of blocks
-    block_size: usize,
+    pub block_size: usize,
     // the target size for a MemTable to reach to become a SsTable
-    target_sst_size: usize,
+    pub target_sst_size: usize,
     // the number of maximum MemTable that can exist, otherwise it will be converted into SsTable
-    num_memtable_limit: usize,
-    compaction_option: CompactionOption,
-    enable_wal: bool,
-    // something related to MVCC, I do not know yet
-    serializable: bool,
+    pub num_memtable_limit: usize,
+    // the number of bits in the bloom filter of a SsTable for each key, more bits means less
+    // false positives, 0 turns the bloom filters off
+    pub bloom_bits_per_key: usize,
+    // how the data blocks of new SsTables are compressed, SsTables that are already written keep
+    // the compression they were written with
+    pub compression: CompressionType,
+    pub compaction_option: CompactionOption,
+    pub enable_wal: bool,
+    // whether transactions check at commit that nothing they read was overwritten by a later
+    // commit, without it they only read from a snapshot (snapshot isolation)
+    pub serializable: bool,
+    // how long a pessimistic transaction waits for the lock of a key before it gives up
+    pub lock_timeout: Duration,
+}
+
+impl Default for LsmStorageConfig {
+    fn default() -> Self {
+        Self {
+            block_size: 4096,
+            target_sst_size: 2 << 20,
+            num_memtable_limit: 50,
+            bloom_bits_per_key: 10,
+            compression: CompressionType::None,
+            compaction_option: CompactionOption::NoCompaction,
+            enable_wal: false,
+            serializable: false,
+            lock_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// One write of a batch, the whole batch is committed with a single timestamp.
+pub enum WriteBatchRecord<T: AsRef<[u8]>> {
+    Put(T, T),
+    Del(T),
+}
+
+impl<T: AsRef<[u8]>> WriteBatchRecord<T> {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            WriteBatchRecord::Put(key, _) | WriteBatchRecord::Del(key) => key.as_ref(),
+        }
+    }
+}
+
+/// A flush or compaction in the background failed. The background thread stops after its first
+/// error, which is kept and returned by every write and by `close` from then on.
+#[derive(Debug, Clone)]
+pub struct BackgroundError(Arc<anyhow::Error>);
+
+impl fmt::Display for BackgroundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a background flush or compaction failed: {:#}", self.0)
+    }
+}
+
+impl std::error::Error for BackgroundError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.0.as_ref().as_ref())
+    }
 }
 
 pub struct LsmStorageInner {
     // the current state of the storage engine
-    state: Arc<RwLock<Arc<LsmStorageState>>>,
+    pub(crate) state: Arc<RwLock<Arc<LsmStorageState>>>,
     // global lock
-    state_lock: Mutex<()>,
+    pub(crate) state_lock: Mutex<()>,
+    // only one flush runs at a time, so the oldest immutable memtable cannot be flushed twice
+    // while the SsTable is built without the state lock
+    flush_lock: Mutex<()>,
     // block cache that can store the closest saved block
-    block_cache: BlockCache,
+    pub(crate) block_cache: Arc<BlockCache>,
     next_sstable_id: AtomicUsize,
     path: PathBuf,
-    config: LsmStorageConfig,
-    compaction_controller: CompactionController,
-    manifest: Option<Manifest>,
-    mvcc: Option<LsmMvccInner>
+    pub(crate) config: LsmStorageConfig,
+    // writers use this to wake the flush thread up as soon as there are too many immutable
+    // memtables, instead of waiting for the next tick
+    flush_wakeup: Sender<()>,
+    flush_wakeup_receiver: Receiver<()>,
+    pub(crate) compaction_controller: CompactionController,
+    // only one compaction runs at a time, a compaction task would not match the state anymore
+    // if another compaction changed the levels under it
+    pub(crate) compaction_lock: Mutex<()>,
+    pub(crate) manifest: Option<Manifest>,
+    pub(crate) mvcc: LsmMvccInner,
+    pub(crate) lock_manager: LockManager,
+    background_error: Mutex<Option<BackgroundError>>,
 }
 
 impl LsmStorageInner {
-    // it is only currently getting from the memtables
-    fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
-        let guard = self.state.read();
-        let snapshot = guard;
+    /// Start the storage engine by either loading an existing directory or creating a new one.
+    pub(crate) fn open(path: impl AsRef<Path>, config: LsmStorageConfig) -> Result<Self> {
+        let path = path.as_ref();
+        // with 0 the flush thread would keep flushing an empty list of immutable memtables
+        ensure!(config.num_memtable_limit >= 1, "num_memtable_limit must be at least 1");
+        config.compaction_option.validate().context("invalid compaction option")?;
+        if !path.exists() {
+            std::fs::create_dir_all(path)
+                .with_context(|| format!("failed to create the database directory {:?}", path))?;
+            // the directory itself is an entry of its parent, which has to be durable as well
+            sync_parent_dir(path)?;
+        }
+        let mut state = LsmStorageState::create(&config);
+        let compaction_controller = CompactionController::new(&config.compaction_option);
+        // 1024 blocks, which is 4MB with the default block size
+        let block_cache = Arc::new(BlockCache::new(1024));
 
-        if let Some(value) = snapshot.memtable.get(Bytes::copy_from_slice(key)) {
-            if value.is_empty() {
-                return Ok(None)
+        let manifest_path = path.join("MANIFEST");
+        let mut next_sstable_id = 0;
+        // the commit timestamps continue from the newest write that survived
+        let mut max_ts = TS_DEFAULT;
+        let manifest = if !manifest_path.exists() {
+            Manifest::create(&manifest_path)?
+        } else {
+            let (manifest, records) = Manifest::recover(&manifest_path)?;
+            // memtables and SsTables share the same id space, the ids in the records must never
+            // be handed out again
+            let mut memtable_ids = Vec::new();
+            for record in records {
+                match record {
+                    ManifestRecord::NewMemtable(id) => {
+                        next_sstable_id = next_sstable_id.max(id + 1);
+                        memtable_ids.push(id);
+                    }
+                    ManifestRecord::Flush(id) => {
+                        state.apply_flush(&compaction_controller, id);
+                        memtable_ids.retain(|memtable_id| *memtable_id != id);
+                    }
+                    ManifestRecord::Compaction { l0_sstables, levels } => {
+                        // the output of a compaction takes its ids from the same counter
+                        let max_id = l0_sstables
+                            .iter()
+                            .chain(levels.iter().flat_map(|(_, ids)| ids.iter()))
+                            .max();
+                        if let Some(max_id) = max_id {
+                            next_sstable_id = next_sstable_id.max(max_id + 1);
+                        }
+                        state.l0_sstables = l0_sstables;
+                        state.levels = levels;
+                    }
+                }
             }
-            return Ok(Some(value))
+
+            state.fit_levels(&config.compaction_option);
+
+            let sst_ids = state
+                .l0_sstables
+                .iter()
+                .chain(state.levels.iter().flat_map(|(_, ids)| ids.iter()))
+                .copied()
+                .collect::<Vec<_>>();
+            for sst_id in sst_ids {
+                let file = FileObject::open(&Self::path_of_sst_static(path, sst_id))
+                    .with_context(|| format!("failed to open SsTable {}", sst_id))?;
+                let sst = SsTable::open(file, Some(block_cache.clone()), sst_id)?;
+                max_ts = max_ts.max(sst.max_ts());
+                state.sstables.insert(sst_id, Arc::new(sst));
+            }
+
+            // the memtables that were never flushed can only be rebuilt from their WALs, replay
+            // them from the oldest to the newest so the newest ends up first in immut_memtable
+            for &id in &memtable_ids {
+                let wal_path = Self::path_of_wal_static(path, id);
+                if !wal_path.exists() {
+                    // the WAL was disabled, whatever was in this memtable is gone
+                    continue;
+                }
+                let memtable = MemTable::recover_from_wal(id, &wal_path)?;
+                if memtable.is_empty() {
+                    // there is nothing to flush, and only non-empty memtables are ever frozen
+                    std::fs::remove_file(&wal_path)?;
+                    sync_dir(path)?;
+                } else {
+                    max_ts = max_ts.max(memtable.max_ts());
+                    state.immut_memtable.insert(0, Arc::new(memtable));
+                }
+            }
+
+            // a crash can leave files behind that no record mentions, like the output of a
+            // compaction that never got recorded or a WAL written by an older version before its
+            // record, their ids are handed out again so they have to go
+            let mut removed_orphan = false;
+            for entry in std::fs::read_dir(path)? {
+                let file_path = entry?.path();
+                let id = file_path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<usize>().ok());
+                let orphan = match (id, file_path.extension().and_then(|ext| ext.to_str())) {
+                    (Some(id), Some("sst")) => !state.sstables.contains_key(&id),
+                    (Some(id), Some("wal")) => !memtable_ids.contains(&id),
+                    _ => false,
+                };
+                if orphan {
+                    std::fs::remove_file(&file_path)
+                        .with_context(|| format!("failed to remove orphan {:?}", file_path))?;
+                    removed_orphan = true;
+                }
+            }
+            if removed_orphan {
+                sync_dir(path)?;
+            }
+            manifest
+        };
+
+        let memtable_id = next_sstable_id;
+        next_sstable_id += 1;
+        // recorded before the WAL exists, a crash in between leaves a record without a WAL,
+        // which is skipped, rather than a WAL without a record
+        manifest.add_record_when_init(ManifestRecord::NewMemtable(memtable_id))?;
+        state.memtable = if config.enable_wal {
+            Arc::new(MemTable::create_with_wal(memtable_id, Self::path_of_wal_static(path, memtable_id))?)
+        } else {
+            Arc::new(MemTable::create(memtable_id))
+        };
+
+        // one pending wake-up is enough, the flush thread drains everything that is over the limit
+        let (flush_wakeup, flush_wakeup_receiver) = crossbeam_channel::bounded(1);
+        Ok(Self {
+            state: Arc::new(RwLock::new(Arc::new(state))),
+            state_lock: Mutex::new(()),
+            flush_lock: Mutex::new(()),
+            block_cache,
+            next_sstable_id: AtomicUsize::new(next_sstable_id),
+            path: path.to_path_buf(),
+            flush_wakeup,
+            flush_wakeup_receiver,
+            compaction_controller,
+            compaction_lock: Mutex::new(()),
+            config,
+            manifest: Some(manifest),
+            mvcc: LsmMvccInner::new(max_ts),
+            lock_manager: LockManager::new(),
+            background_error: Mutex::new(None),
+        })
+    }
+
+    /// Look the key up as of the latest commit.
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.get_with_ts(key, None)
+    }
+
+    /// Look the key up from the newest data to the oldest data, which is the memtable, the
+    /// immutable memtables, L0 and then every level. The first layer that has a version of the
+    /// key committed at or before `read_ts` (the latest commit if it is None) decides the result,
+    /// even if that version is a tombstone.
+    pub(crate) fn get_with_ts(&self, key: &[u8], read_ts: Option<u64>) -> Result<Option<Bytes>> {
+        let (snapshot, read_ts) = self.read_state(read_ts);
+        let key = KeySlice::from_slice(key, read_ts);
+
+        if let Some(value) = snapshot.memtable.get(key) {
+            return Ok(Self::filter_tombstone(value));
         }
 
         for memtable in snapshot.immut_memtable.iter() {
-            if let Some(value) = memtable.get(Bytes::copy_from_slice(key)) {
-                if value.is_empty() {
-                    return Ok(None)
-                }
-                return Ok(Some(value))
+            if let Some(value) = memtable.get(key) {
+                return Ok(Self::filter_tombstone(value));
+            }
+        }
+
+        // L0 SsTables overlap with each other, so every one of them has to be checked, newest first
+        for sst_id in snapshot.l0_sstables.iter() {
+            if let Some(value) = snapshot.sstables[sst_id].get(key)? {
+                return Ok(Self::filter_tombstone(value));
+            }
+        }
+
+        // SsTables inside a level are sorted and do not overlap, and the versions of a key are
+        // never split between two SsTables, so at most one of them can have the key
+        for (_, level_sst_ids) in snapshot.levels.iter() {
+            let idx = level_sst_ids
+                .partition_point(|sst_id| snapshot.sstables[sst_id].last_key().key_ref() < key.key_ref());
+            if let Some(sst_id) = level_sst_ids.get(idx)
+                && let Some(value) = snapshot.sstables[sst_id].get(key)?
+            {
+                return Ok(Self::filter_tombstone(value));
             }
         }
         Ok(None)
     }
 
-    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
-        assert!(!key.is_empty(), "key cannot be empty");
-        assert!(!value.is_empty(), "value cannot be empty");
-
+    // A reader without a registered read timestamp takes the latest commit together with the
+    // state, the SsTables in that state were compacted with a watermark at or below that commit
+    // and still have every version the reader can see. Taken apart, a compaction with a newer
+    // watermark could replace the SsTables in between.
+    fn read_state(&self, read_ts: Option<u64>) -> (Arc<LsmStorageState>, u64) {
         let guard = self.state.read();
-        guard.memtable.put(key, value)?;
-        let size = guard.memtable.approximate_size();
+        let read_ts = read_ts.unwrap_or_else(|| self.mvcc.latest_commit_ts());
+        (Arc::clone(&guard), read_ts)
+    }
 
-        self.try_freeze_memtable(size)
+    fn filter_tombstone(value: Bytes) -> Option<Bytes> {
+        if value.is_empty() {
+            None
+        } else {
+            Some(value)
+        }
     }
 
-    pub fn delete(&self, key: &[u8]) -> Result<()> {
-        assert!(!key.is_empty(), "key cannot be empty");
+    pub(crate) fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.write_batch(&[WriteBatchRecord::Put(key, value)])
+    }
+
+    pub(crate) fn delete(&self, key: &[u8]) -> Result<()> {
+        self.write_batch(&[WriteBatchRecord::Del(key)])
+    }
+
+    /// Write every record of the batch with the same commit timestamp, a reader sees either all
+    /// of them or none of them.
+    pub(crate) fn write_batch<T: AsRef<[u8]>>(&self, batch: &[WriteBatchRecord<T>]) -> Result<()> {
         let size;
         {
-            let guard = self.state.read();
-            guard.memtable.put(key, b"")?;
-            size = guard.memtable.approximate_size();
+            let write_lock = self.mvcc.write_lock.lock();
+            size = self.write_batch_locked(&write_lock, batch)?.1;
         }
-        self.try_freeze_memtable(size)?;
-        Ok(())
+        self.try_freeze_memtable(size)
+    }
+
+    // A delete is a write of an empty value. The batch gets the next commit timestamp, and the
+    // timestamp is only published once the whole batch is in the memtable, so a reader never
+    // sees half of what was committed before its read timestamp. Every record is a WAL record of
+    // its own though, so a crash while a batch that was not synced yet is being written out may
+    // keep a part of it.
+    //
+    // Returns the commit timestamp and the size of the memtable, which the caller passes to
+    // `try_freeze_memtable` after releasing the write lock.
+    pub(crate) fn write_batch_locked<T: AsRef<[u8]>>(
+        &self,
+        _write_lock_observer: &MutexGuard<'_, ()>,
+        batch: &[WriteBatchRecord<T>],
+    ) -> Result<(u64, usize)> {
+        self.check_background_error()?;
+        let ts = self.mvcc.latest_commit_ts() + 1;
+        let batch_with_ts = batch
+            .iter()
+            .map(|record| {
+                let (key, value) = match record {
+                    WriteBatchRecord::Put(key, value) => {
+                        assert!(!value.as_ref().is_empty(), "value cannot be empty");
+                        (key.as_ref(), value.as_ref())
+                    }
+                    WriteBatchRecord::Del(key) => (key.as_ref(), &b""[..]),
+                };
+                assert!(!key.is_empty(), "key cannot be empty");
+                (KeySlice::from_slice(key, ts), value)
+            })
+            .collect::<Vec<_>>();
+        let guard = self.state.read();
+        guard.memtable.put_batch(&batch_with_ts)?;
+        let size = guard.memtable.approximate_size();
+        if self.config.serializable {
+            self.mvcc.record_commit(ts, batch.iter().map(|record| record.key()));
+        }
+        self.mvcc.update_commit_ts(ts);
+        Ok((ts, size))
     }
 
-    fn try_freeze_memtable(&self, size: usize) -> Result<()> {
+    pub(crate) fn try_freeze_memtable(&self, size: usize) -> Result<()> {
         if size > self.config.target_sst_size {
-            let _state_lock = self.state_lock.lock();
+            let state_lock = self.state_lock.lock();
             let guard = self.state.read();
             // the reason for recheck is that is the case that there are two threads already executing
             // the try_freeze_memtable function in put function, and the first thread may lock the state_lock
@@ -114,15 +503,26 @@ impl LsmStorageInner {
             // get the approximate_size again.
             if guard.memtable.approximate_size() > self.config.target_sst_size {
                 drop(guard);
-                self.freeze_memtable()?
+                self.freeze_memtable(&state_lock)?
             }
         }
         Ok(())
     }
 
-    fn freeze_memtable(&self) -> Result<()> {
+    fn freeze_memtable(&self, state_lock_observer: &MutexGuard<'_, ()>) -> Result<()> {
         let new_memtable_id = self.next_sst_id();
-        let new_memtable = Arc::new(MemTable::create(new_memtable_id));
+        // recorded before its WAL is created, as in open, and before the memtable becomes
+        // visible, so it can always be found again after a restart
+        self.manifest
+            .as_ref()
+            .unwrap()
+            .add_record(state_lock_observer, ManifestRecord::NewMemtable(new_memtable_id))?;
+        let new_memtable = if self.config.enable_wal {
+            Arc::new(MemTable::create_with_wal(new_memtable_id, self.path_of_wal(new_memtable_id))?)
+        } else {
+            Arc::new(MemTable::create(new_memtable_id))
+        };
+        let old_memtable;
         {
             let mut guard = self.state.write();
             // guard itself is a pointer that points to the Arc pointer that points to the real data
@@ -131,37 +531,1059 @@ impl LsmStorageInner {
             // inside of Arc<T>, and .clone() is to get the underlying data that T points to on the heap
             // to pass it into the std::mem::replace() function
             let mut snapshot = guard.as_ref().clone();
-            let old_memtable = std::mem::replace(&mut snapshot.memtable, new_memtable);
-            snapshot.immut_memtable.insert(0, old_memtable);
+            old_memtable = std::mem::replace(&mut snapshot.memtable, new_memtable);
+            snapshot.immut_memtable.insert(0, old_memtable.clone());
             *guard = Arc::new(snapshot);
         }
+        // nothing is written into the old memtable anymore, so its WAL can be completed
+        old_memtable.sync_wal()?;
+        if self.state.read().immut_memtable.len() >= self.config.num_memtable_limit {
+            // the channel is full when a wake-up is already pending, which is fine
+            let _ = self.flush_wakeup.try_send(());
+        }
         Ok(())
     }
 
+    /// Turn the oldest immutable memtable into a L0 SsTable.
+    pub(crate) fn force_flush_next_immut_memtable(&self) -> Result<()> {
+        let _flush_lock = self.flush_lock.lock();
+
+        // only a flush removes immutable memtables, so the oldest one stays the same until this
+        // flush is done
+        let memtable_to_flush = {
+            let guard = self.state.read();
+            match guard.immut_memtable.last() {
+                Some(memtable) => memtable.clone(),
+                None => return Ok(()),
+            }
+        };
+
+        // the SsTable reuses the id of the memtable, so the ids in l0_sstables keep the same
+        // order as the memtables they come from
+        let sst_id = memtable_to_flush.id();
+        let mut builder = self.new_sst_builder(sst_id)?;
+        memtable_to_flush.flush(&mut builder)?;
+        let sst = Arc::new(builder.build(sst_id, Some(self.block_cache.clone()))?);
+
+        // the SsTable has to be durable before the manifest points to it
+        self.sync_dir()?;
+
+        // the state lock is only needed from here on, so a writer that freezes a memtable does
+        // not wait for the SsTable to be written
+        {
+            let state_lock = self.state_lock.lock();
+            self.manifest
+                .as_ref()
+                .unwrap()
+                .add_record(&state_lock, ManifestRecord::Flush(sst_id))?;
+            let mut guard = self.state.write();
+            let mut snapshot = guard.as_ref().clone();
+            let memtable = snapshot.immut_memtable.pop().unwrap();
+            assert_eq!(memtable.id(), sst_id);
+            snapshot.apply_flush(&self.compaction_controller, sst_id);
+            snapshot.sstables.insert(sst_id, sst);
+            *guard = Arc::new(snapshot);
+        }
+
+        // the WAL is useless now that the manifest says the data lives in the SsTable
+        match std::fs::remove_file(self.path_of_wal(sst_id)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            Err(_) => Ok(()),
+            Ok(()) => self.sync_dir(),
+        }
+    }
+
+    /// Freeze the current memtable (if it has anything in it) and flush every immutable memtable
+    /// into L0.
+    pub(crate) fn force_flush(&self) -> Result<()> {
+        if !self.state.read().memtable.is_empty() {
+            let state_lock = self.state_lock.lock();
+            // recheck for the same reason as in try_freeze_memtable
+            if !self.state.read().memtable.is_empty() {
+                self.freeze_memtable(&state_lock)?;
+            }
+        }
+        while !self.state.read().immut_memtable.is_empty() {
+            self.force_flush_next_immut_memtable()?;
+        }
+        self.sync_dir()
+    }
+
+    /// Flush the oldest immutable memtables until the number of memtables is within
+    /// `num_memtable_limit` again, this is what the flush thread runs every time it wakes up.
+    fn trigger_flush(&self) -> Result<()> {
+        // the active memtable counts as well
+        while self.state.read().immut_memtable.len() >= self.config.num_memtable_limit {
+            self.force_flush_next_immut_memtable()?;
+        }
+        Ok(())
+    }
+
+    /// Spawn the thread that flushes immutable memtables in the background, it stops once
+    /// something is sent through `stop` or the sender is dropped.
+    pub(crate) fn spawn_flush_thread(self: &Arc<Self>, stop: Receiver<()>) -> Result<JoinHandle<()>> {
+        let this = self.clone();
+        let handle = std::thread::Builder::new()
+            .name("lsm-flush".to_string())
+            .spawn(move || {
+                let ticker = crossbeam_channel::tick(Duration::from_millis(50));
+                loop {
+                    crossbeam_channel::select! {
+                        recv(ticker) -> _ => {},
+                        recv(this.flush_wakeup_receiver) -> _ => {},
+                        recv(stop) -> _ => return,
+                    }
+                    if let Err(e) = this.trigger_flush() {
+                        this.set_background_error(e);
+                        return;
+                    }
+                }
+            })?;
+        Ok(handle)
+    }
+
+    pub(crate) fn set_background_error(&self, e: anyhow::Error) {
+        let mut background_error = self.background_error.lock();
+        if background_error.is_none() {
+            *background_error = Some(BackgroundError(Arc::new(e)));
+        }
+    }
+
+    pub(crate) fn check_background_error(&self) -> Result<()> {
+        match &*self.background_error.lock() {
+            Some(e) => Err(e.clone().into()),
+            None => Ok(()),
+        }
+    }
+
+    fn path_of_sst_static(path: impl AsRef<Path>, id: usize) -> PathBuf {
+        path.as_ref().join(format!("{}.sst", id))
+    }
+
+    pub(crate) fn path_of_sst(&self, id: usize) -> PathBuf {
+        Self::path_of_sst_static(&self.path, id)
+    }
+
+    fn path_of_wal_static(path: impl AsRef<Path>, id: usize) -> PathBuf {
+        path.as_ref().join(format!("{}.wal", id))
+    }
+
+    pub(crate) fn path_of_wal(&self, id: usize) -> PathBuf {
+        Self::path_of_wal_static(&self.path, id)
+    }
+
+    /// Make sure every write acknowledged so far survives a crash.
+    pub(crate) fn sync(&self) -> Result<()> {
+        self.state.read().memtable.sync_wal()
+    }
+
+    /// Create the file of SsTable `sst_id` and a builder that streams into it.
+    pub(crate) fn new_sst_builder(&self, sst_id: usize) -> Result<SsTableBuilder> {
+        SsTableBuilder::create(
+            self.path_of_sst(sst_id),
+            self.config.block_size,
+            self.config.bloom_bits_per_key,
+            self.config.compression,
+        )
+    }
+
     pub(crate) fn next_sst_id(&self) -> usize {
         self.next_sstable_id.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
     }
 
-    /// Create an iterator over a range of keys.
-    pub fn scan(
+    pub(crate) fn sync_dir(&self) -> Result<()> {
+        sync_dir(&self.path)
+    }
+
+    /// Create an iterator over a range of keys as of the latest commit.
+    pub(crate) fn scan(
         &self,
         lower: Bound<&[u8]>,
         upper: Bound<&[u8]>,
     ) -> Result<FusedIterator<LsmIterator>> {
-        let snapshot = {
-            let guard = self.state.read();
-            Arc::clone(&guard)
-        }; // drop global lock here
+        self.scan_with_ts(lower, upper, None)
+    }
 
+    /// Create an iterator over a range of keys that only sees the versions committed at or
+    /// before `read_ts` (the latest commit if it is None).
+    pub(crate) fn scan_with_ts(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        read_ts: Option<u64>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        let (snapshot, read_ts) = self.read_state(read_ts);
+
+        // the bounds are on user keys, so they have to include (or exclude) every version
+        let (key_lower, key_upper) = (
+            match lower {
+                Bound::Included(key) => Bound::Included(KeySlice::from_slice(key, TS_RANGE_BEGIN)),
+                Bound::Excluded(key) => Bound::Excluded(KeySlice::from_slice(key, TS_RANGE_END)),
+                Bound::Unbounded => Bound::Unbounded,
+            },
+            match upper {
+                Bound::Included(key) => Bound::Included(KeySlice::from_slice(key, TS_RANGE_END)),
+                Bound::Excluded(key) => Bound::Excluded(KeySlice::from_slice(key, TS_RANGE_BEGIN)),
+                Bound::Unbounded => Bound::Unbounded,
+            },
+        );
         let mut memtable_iters = Vec::with_capacity(snapshot.immut_memtable.len() + 1);
-        memtable_iters.push(Box::new(snapshot.memtable.scan(lower, upper)));
+        memtable_iters.push(Box::new(snapshot.memtable.scan(key_lower, key_upper)));
         for memtable in snapshot.immut_memtable.iter() {
-            memtable_iters.push(Box::new(memtable .scan(lower, upper)));
+            memtable_iters.push(Box::new(memtable.scan(key_lower, key_upper)));
+        }
+        let memtable_iter = MergeIterator::create(memtable_iters);
+
+        let mut l0_iters = Vec::with_capacity(snapshot.l0_sstables.len());
+        for sst_id in snapshot.l0_sstables.iter() {
+            let table = snapshot.sstables[sst_id].clone();
+            if range_overlap(lower, upper, table.first_key().key_ref(), table.last_key().key_ref()) {
+                l0_iters.push(Box::new(Self::sst_iter_from_lower_bound(table, lower)?));
+            }
+        }
+        let l0_iter = MergeIterator::create(l0_iters);
+
+        let mut level_iters = Vec::with_capacity(snapshot.levels.len());
+        for (_, level_sst_ids) in snapshot.levels.iter() {
+            let level_ssts = level_sst_ids
+                .iter()
+                .map(|sst_id| snapshot.sstables[sst_id].clone())
+                .filter(|table| {
+                    range_overlap(lower, upper, table.first_key().key_ref(), table.last_key().key_ref())
+                })
+                .collect::<Vec<_>>();
+            let level_iter = match lower {
+                Bound::Included(key) => SstConcatIterator::create_and_seek_to_key(
+                    level_ssts,
+                    KeySlice::from_slice(key, TS_RANGE_BEGIN),
+                )?,
+                Bound::Excluded(key) => {
+                    let mut iter = SstConcatIterator::create_and_seek_to_key(
+                        level_ssts,
+                        KeySlice::from_slice(key, TS_RANGE_BEGIN),
+                    )?;
+                    while iter.is_valid() && iter.key().key_ref() == key {
+                        iter.next()?;
+                    }
+                    iter
+                }
+                Bound::Unbounded => SstConcatIterator::create_and_seek_to_first(level_ssts)?,
+            };
+            level_iters.push(Box::new(level_iter));
+        }
+        let level_iter = MergeIterator::create(level_iters);
+
+        let iter = TwoMergeIterator::create(TwoMergeIterator::create(memtable_iter, l0_iter)?, level_iter)?;
+        Ok(FusedIterator::new(LsmIterator::new(iter, map_bound(upper), read_ts)?))
+    }
+
+    fn sst_iter_from_lower_bound(table: Arc<SsTable>, lower: Bound<&[u8]>) -> Result<SsTableIterator> {
+        let iter = match lower {
+            Bound::Included(key) => {
+                SsTableIterator::create_and_seek_to_key(table, KeySlice::from_slice(key, TS_RANGE_BEGIN))?
+            }
+            Bound::Excluded(key) => {
+                let mut iter =
+                    SsTableIterator::create_and_seek_to_key(table, KeySlice::from_slice(key, TS_RANGE_BEGIN))?;
+                while iter.is_valid() && iter.key().key_ref() == key {
+                    iter.next()?;
+                }
+                iter
+            }
+            Bound::Unbounded => SsTableIterator::create_and_seek_to_first(table)?,
+        };
+        Ok(iter)
+    }
+}
+
+/// Make the content of `file`, which lives at `path`, durable. Every fsync of the storage goes
+/// through here or `sync_dir`, so the tests can see what was made durable and in which order.
+pub(crate) fn sync_file(file: &std::fs::File, path: &Path) -> Result<()> {
+    file.sync_all().with_context(|| format!("failed to sync {:?}", path))?;
+    #[cfg(test)]
+    sync_log::record(sync_log::Synced::File(path.to_path_buf()));
+    Ok(())
+}
+
+/// Make the creation, rename or removal of the entries of the directory `path` durable. Syncing
+/// a file only covers its content, the entry pointing at it lives in the directory.
+pub(crate) fn sync_dir(path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    std::fs::File::open(path)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("failed to sync directory {:?}", path))?;
+    #[cfg(test)]
+    sync_log::record(sync_log::Synced::Dir(path.to_path_buf()));
+    Ok(())
+}
+
+/// `sync_dir` on the directory that contains `path`.
+pub(crate) fn sync_parent_dir(path: impl AsRef<Path>) -> Result<()> {
+    match path.as_ref().parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => sync_dir(parent),
+        // a relative path with a single component lives in the working directory
+        _ => sync_dir("."),
+    }
+}
+
+// Whatever survives a power loss is what was synced, so the order of the syncs is what the
+// tests check instead of crashing a process, whose writes the page cache would keep anyway.
+#[cfg(test)]
+pub(crate) mod sync_log {
+    use std::path::{Path, PathBuf};
+    use parking_lot::Mutex;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub(crate) enum Synced {
+        File(PathBuf),
+        Dir(PathBuf),
+    }
+
+    // shared by every test running in parallel, each one only looks at its own directory
+    static LOG: Mutex<Vec<Synced>> = Mutex::new(Vec::new());
+
+    pub(crate) fn record(synced: Synced) {
+        LOG.lock().push(synced);
+    }
+
+    /// Remove the syncs of everything under `dir` from the log and return them, with the paths
+    /// made relative to `dir`.
+    pub(crate) fn take(dir: &Path) -> Vec<Synced> {
+        let mut log = LOG.lock();
+        let mut taken = Vec::new();
+        log.retain(|synced| {
+            let (Synced::File(path) | Synced::Dir(path)) = synced;
+            let Ok(relative) = path.strip_prefix(dir) else {
+                return true;
+            };
+            taken.push(match synced {
+                Synced::File(_) => Synced::File(relative.to_path_buf()),
+                Synced::Dir(_) => Synced::Dir(relative.to_path_buf()),
+            });
+            false
+        });
+        taken
+    }
+}
+
+/// Whether the range given by `lower` and `upper` has anything in common with the key range
+/// `[first_key, last_key]` of a SsTable.
+fn range_overlap(lower: Bound<&[u8]>, upper: Bound<&[u8]>, first_key: &[u8], last_key: &[u8]) -> bool {
+    match upper {
+        Bound::Excluded(key) if key <= first_key => return false,
+        Bound::Included(key) if key < first_key => return false,
+        _ => {}
+    }
+    match lower {
+        Bound::Excluded(key) if key >= last_key => return false,
+        Bound::Included(key) if key > last_key => return false,
+        _ => {}
+    }
+    true
+}
+
+/// The handle of the database, this is the only thing that users outside of the crate interact
+/// with. All the real work is delegated to `LsmStorageInner`.
+pub struct MiniLsm {
+    pub(crate) inner: Arc<LsmStorageInner>,
+    // sending anything (or dropping the sender) stops the flush thread
+    flush_stop: Sender<()>,
+    flush_thread: Mutex<Option<JoinHandle<()>>>,
+    // the same for the compaction thread, which does not exist without compaction
+    compaction_stop: Sender<()>,
+    compaction_thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Drop for MiniLsm {
+    // we cannot report an error from here, so only the background thread is stopped and the WAL
+    // is synced on a best-effort basis, the memtables are only flushed by `close`
+    fn drop(&mut self) {
+        self.stop_background_threads();
+        self.inner.sync().ok();
+    }
+}
+
+impl MiniLsm {
+    /// Open the database at `path`, the directory is created if it does not exist yet.
+    pub fn open(path: impl AsRef<Path>, config: LsmStorageConfig) -> Result<Arc<Self>> {
+        let inner = Arc::new(LsmStorageInner::open(path, config)?);
+        let (flush_stop, flush_stop_receiver) = crossbeam_channel::unbounded();
+        let flush_thread = inner.spawn_flush_thread(flush_stop_receiver)?;
+        let (compaction_stop, compaction_stop_receiver) = crossbeam_channel::unbounded();
+        let compaction_thread = inner.spawn_compaction_thread(compaction_stop_receiver)?;
+        Ok(Arc::new(Self {
+            inner,
+            flush_stop,
+            flush_thread: Mutex::new(Some(flush_thread)),
+            compaction_stop,
+            compaction_thread: Mutex::new(compaction_thread),
+        }))
+    }
+
+    fn stop_background_threads(&self) {
+        // the threads never panic on purpose, if one did there is nothing left to clean up
+        self.compaction_stop.send(()).ok();
+        if let Some(compaction_thread) = self.compaction_thread.lock().take() {
+            let _ = compaction_thread.join();
         }
-        let iter = MergeIterator::create(memtable_iters);
-        Ok(FusedIterator::new(LsmIterator::new(iter)?))
+        self.flush_stop.send(()).ok();
+        if let Some(flush_thread) = self.flush_thread.lock().take() {
+            let _ = flush_thread.join();
+        }
+    }
+
+    /// Shut down the database, after this returns everything written so far is on disk. Fails
+    /// with `BackgroundError` if a flush or compaction in the background failed before.
+    pub fn close(&self) -> Result<()> {
+        self.stop_background_threads();
+        self.inner.check_background_error()?;
+        self.inner.force_flush()
+    }
+
+    /// Sync the WAL of the current memtable, every write before this call survives a crash
+    /// when `enable_wal` is set.
+    pub fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    /// Flush everything in memory into L0 SsTables.
+    pub fn force_flush(&self) -> Result<()> {
+        self.inner.force_flush()
+    }
+
+    /// Rewrite every SsTable into one sorted run at the bottom level, what is still in the
+    /// memtables is not included.
+    pub fn force_full_compaction(&self) -> Result<()> {
+        self.inner.force_full_compaction()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.inner.get(key)
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.inner.put(key, value)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.inner.delete(key)
+    }
+
+    pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<FusedIterator<LsmIterator>> {
+        self.inner.scan(lower, upper)
+    }
+
+    /// Take a snapshot of everything committed so far, the reads through it keep seeing this
+    /// state until it is dropped.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self.inner.clone())
+    }
+
+    /// Start a transaction that reads from a snapshot taken now and commits all its writes at
+    /// once. With `serializable` set, the commit fails with `TxnError::Conflict` if another commit
+    /// after the snapshot wrote a key the transaction read.
+    pub fn new_txn(&self) -> Arc<Transaction> {
+        Transaction::new(self.inner.clone(), false)
+    }
+
+    /// Start a transaction that locks every key it writes, and every key it reads with
+    /// `get_for_update`, until it commits or rolls back. Waiting for a lock fails with
+    /// `TxnError::LockTimeout` after `lock_timeout`, or with `TxnError::Deadlock` if the waiting
+    /// transactions would block each other forever. The locks only order pessimistic
+    /// transactions against each other, `put`, `delete` and optimistic transactions do not take
+    /// them.
+    pub fn new_pessimistic_txn(&self) -> Arc<Transaction> {
+        Transaction::new(self.inner.clone(), true)
     }
 }
 
-pub struct MiniLsm {}
 
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use anyhow::Result;
+    use super::sync_log::{self, Synced};
+    use super::*;
+    use crate::compact::TieredCompactionOption;
+    use crate::iterator::StorageIterator;
+    use crate::table::{FileObject, SsTableIterator};
+
+    #[test]
+    fn flushed_memtables_become_l0_sstables() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = MiniLsm::open(dir.path(), LsmStorageConfig::default())?;
+        for i in 0..100 {
+            db.put(format!("key_{:03}", i).as_bytes(), format!("value_{}", i).as_bytes())?;
+        }
+        db.delete(b"key_050")?;
+        db.force_flush()?;
+        db.put(b"key_050", b"again")?;
+        db.force_flush()?;
+        // nothing left to flush, so no empty SsTable either
+        db.force_flush()?;
+
+        let snapshot = db.inner.state.read().clone();
+        assert!(snapshot.memtable.is_empty());
+        assert!(snapshot.immut_memtable.is_empty());
+        let (newer, older) = (snapshot.l0_sstables[0], snapshot.l0_sstables[1]);
+        assert_eq!(snapshot.l0_sstables.len(), 2);
+        assert!(newer > older);
+        assert_eq!(snapshot.sstables.len(), 2);
+        drop(snapshot);
+        drop(db);
+
+        // with the memtables gone, the files are all that is left
+        let read = |id: usize| -> Result<Vec<_>> {
+            let file = FileObject::open(&dir.path().join(format!("{}.sst", id)))?;
+            let mut iter = SsTableIterator::create_and_seek_to_first(Arc::new(SsTable::open(file, None, id)?))?;
+            let mut entries = Vec::new();
+            while iter.is_valid() {
+                entries.push((iter.key().key_ref().to_vec(), iter.key().ts(), iter.value().to_vec()));
+                iter.next()?;
+            }
+            Ok(entries)
+        };
+        // the delete is flushed as an empty value in front of the version it deletes
+        let mut expected = (0..100)
+            .map(|i| (format!("key_{:03}", i).into_bytes(), i as u64 + 1, format!("value_{}", i).into_bytes()))
+            .collect::<Vec<_>>();
+        expected.insert(50, (b"key_050".to_vec(), 101, Vec::new()));
+        assert_eq!(read(older)?, expected);
+        assert_eq!(read(newer)?, vec![(b"key_050".to_vec(), 102, b"again".to_vec())]);
+        Ok(())
+    }
+
+    #[test]
+    fn get_goes_from_the_memtables_through_l0_to_the_levels() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = MiniLsm::open(
+            dir.path(),
+            LsmStorageConfig {
+                // a few entries per block
+                block_size: 64,
+                ..Default::default()
+            },
+        )?;
+        let key = |i: usize| format!("key_{:03}", i).into_bytes();
+        // what each layer writes, None being a delete, from the oldest layer to the newest
+        let layers: [Vec<(usize, Option<&str>)>; 5] = [
+            (0..100).map(|i| (i, Some("l1"))).collect(),
+            (0..100).step_by(2).map(|i| (i, Some("l0"))).collect(),
+            vec![(10, None), (11, None), (12, Some("l0_newer"))],
+            vec![(20, Some("immutable")), (21, None)],
+            vec![(30, Some("memtable")), (31, None), (10, Some("memtable"))],
+        ];
+        let write = |layer: &[(usize, Option<&str>)]| -> Result<()> {
+            for (i, value) in layer {
+                match value {
+                    Some(value) => db.put(&key(*i), format!("{}_{}", value, i).as_bytes())?,
+                    None => db.delete(&key(*i))?,
+                }
+            }
+            Ok(())
+        };
+
+        // the oldest layer is split into two SsTables that are moved into L1, like a compaction
+        // would leave them
+        write(&layers[0][..50])?;
+        db.force_flush()?;
+        write(&layers[0][50..])?;
+        db.force_flush()?;
+        {
+            let mut guard = db.inner.state.write();
+            let mut snapshot = guard.as_ref().clone();
+            let mut l1 = std::mem::take(&mut snapshot.l0_sstables);
+            l1.reverse();
+            snapshot.levels[0].1 = l1;
+            *guard = Arc::new(snapshot);
+        }
+        write(&layers[1])?;
+        db.force_flush()?;
+        write(&layers[2])?;
+        db.force_flush()?;
+        write(&layers[3])?;
+        db.inner.freeze_memtable(&db.inner.state_lock.lock())?;
+        write(&layers[4])?;
+
+        let snapshot = db.inner.state.read().clone();
+        assert_eq!(snapshot.levels[0].1.len(), 2);
+        assert_eq!(snapshot.l0_sstables.len(), 2);
+        assert_eq!(snapshot.immut_memtable.len(), 1);
+        let mut expected = HashMap::new();
+        for layer in &layers {
+            for (i, value) in layer {
+                expected.insert(*i, value.map(|value| format!("{}_{}", value, i).into_bytes()));
+            }
+        }
+        for i in 0..100 {
+            assert_eq!(db.get(&key(i))?.map(|v| v.to_vec()), expected[&i], "key {}", i);
+        }
+        // before, after and between the keys of the SsTables
+        for missing in [&b"a"[..], b"key_0305", b"key_100", b"z"] {
+            assert_eq!(db.get(missing)?, None);
+        }
+        // a seek leaves the iterator at the entry it found, so that next goes on from there
+        let sst = snapshot.sstables[&snapshot.levels[0].1[0]].clone();
+        let mut iter = SsTableIterator::create_and_seek_to_key(sst, KeySlice::from_slice(&key(25), TS_RANGE_BEGIN))?;
+        for i in 25..50 {
+            assert_eq!(iter.key().key_ref(), key(i));
+            iter.next()?;
+        }
+        assert!(!iter.is_valid());
+        Ok(())
+    }
+
+    #[test]
+    fn scan_merges_every_layer_within_the_bounds() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = MiniLsm::open(
+            dir.path(),
+            LsmStorageConfig {
+                block_size: 64,
+                ..Default::default()
+            },
+        )?;
+        let key = |i: usize| format!("key_{:03}", i).into_bytes();
+        let mut expected = std::collections::BTreeMap::new();
+        let mut write = |keys: &mut dyn Iterator<Item = usize>, value: Option<&str>| -> Result<()> {
+            for i in keys {
+                match value {
+                    Some(value) => db.put(&key(i), format!("{}_{}", value, i).as_bytes())?,
+                    None => db.delete(&key(i))?,
+                }
+                expected.insert(key(i), value.map(|value| format!("{}_{}", value, i).into_bytes()));
+            }
+            Ok(())
+        };
+
+        // two SsTables in L1 with a gap between them
+        write(&mut (0..50), Some("l1"))?;
+        db.force_flush()?;
+        write(&mut (60..100), Some("l1"))?;
+        db.force_flush()?;
+        {
+            let mut guard = db.inner.state.write();
+            let mut snapshot = guard.as_ref().clone();
+            let mut l1 = std::mem::take(&mut snapshot.l0_sstables);
+            l1.reverse();
+            snapshot.levels[0].1 = l1;
+            *guard = Arc::new(snapshot);
+        }
+        // two L0 SsTables that overlap with each other and with both of L1
+        write(&mut (0..100).step_by(4), Some("l0"))?;
+        db.force_flush()?;
+        write(&mut (30..70).step_by(3), Some("l0_newer"))?;
+        write(&mut (40..50).step_by(2), None)?;
+        db.force_flush()?;
+        write(&mut (45..65).step_by(5), Some("memtable"))?;
+        write(&mut [61, 99].into_iter(), None)?;
+
+        let snapshot = db.inner.state.read().clone();
+        assert_eq!(snapshot.levels[0].1.len(), 2);
+        assert_eq!(snapshot.l0_sstables.len(), 2);
+        let points = [
+            b"a".to_vec(),
+            key(0),
+            key(42),
+            // the last key of the first SsTable in L1, then the gap, then the first key of the
+            // second one
+            key(49),
+            key(50),
+            key(55),
+            b"key_0555".to_vec(),
+            key(60),
+            key(99),
+            b"z".to_vec(),
+        ];
+        let mut bounds = vec![Bound::Unbounded];
+        for point in &points {
+            bounds.push(Bound::Included(point.clone()));
+            bounds.push(Bound::Excluded(point.clone()));
+        }
+        for lower in &bounds {
+            for upper in &bounds {
+                // only the ranges that are not empty, which is what BTreeMap can take
+                if let (Bound::Included(l) | Bound::Excluded(l), Bound::Included(u) | Bound::Excluded(u)) = (lower, upper)
+                    && (l > u || (l == u && !matches!((lower, upper), (Bound::Included(_), Bound::Included(_)))))
+                {
+                    continue;
+                }
+                let want = expected
+                    .range::<Vec<u8>, _>((lower.clone(), upper.clone()))
+                    .filter_map(|(key, value)| value.clone().map(|value| (key.clone(), value)))
+                    .collect::<Vec<_>>();
+                let mut iter = db.scan(lower.as_ref().map(|k| &k[..]), upper.as_ref().map(|k| &k[..]))?;
+                let mut got = Vec::new();
+                while iter.is_valid() {
+                    got.push((iter.key().to_vec(), iter.value().to_vec()));
+                    iter.next()?;
+                }
+                assert_eq!(got, want, "{:?} to {:?}", lower, upper);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn background_flush_error_is_returned_by_writes_and_close() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = MiniLsm::open(
+            dir.path(),
+            LsmStorageConfig {
+                target_sst_size: 1024,
+                num_memtable_limit: 2,
+                ..Default::default()
+            },
+        )?;
+        // a directory where the SsTable should go makes every flush fail
+        for id in 0..1000 {
+            std::fs::create_dir(dir.path().join(format!("{}.sst", id)))?;
+        }
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut i = 0;
+        let e = loop {
+            assert!(Instant::now() < deadline, "the flush error never reached a writer");
+            if let Err(e) = db.put(format!("key{}", i).as_bytes(), b"value") {
+                break e;
+            }
+            i += 1;
+        };
+        assert!(e.downcast_ref::<BackgroundError>().is_some());
+        // the error sticks
+        assert!(db.put(b"key", b"value").unwrap_err().downcast_ref::<BackgroundError>().is_some());
+        assert!(db.close().unwrap_err().downcast_ref::<BackgroundError>().is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn zero_memtable_limit_is_rejected_by_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = LsmStorageConfig {
+            num_memtable_limit: 0,
+            ..Default::default()
+        };
+        assert!(MiniLsm::open(dir.path(), config).is_err());
+    }
+
+    #[test]
+    fn invalid_compaction_option_is_rejected_by_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = LsmStorageConfig {
+            compaction_option: CompactionOption::Leveled(LeveledCompactionOption {
+                level_size_multiplier: 10,
+                base_level_size_mb: 1,
+                max_levels: 0,
+                level0_file_num_compaction_trigger: 2,
+            }),
+            ..Default::default()
+        };
+        assert!(MiniLsm::open(dir.path(), config).is_err());
+        let config = LsmStorageConfig {
+            compaction_option: CompactionOption::Simple(SimpleLeveledCompactionOption {
+                size_ratio_percent: 200,
+                level0_file_num_compaction_trigger: 0,
+                max_levels: 3,
+            }),
+            ..Default::default()
+        };
+        assert!(MiniLsm::open(dir.path(), config).is_err());
+    }
+
+    #[test]
+    fn entries_larger_than_64k_round_trip_through_small_blocks() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = || LsmStorageConfig {
+            block_size: 256,
+            enable_wal: true,
+            ..Default::default()
+        };
+        let big_key = vec![b'k'; 70 * 1024];
+        let big_value = (0..100 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+        let db = MiniLsm::open(dir.path(), config())?;
+        db.put(b"a", b"small")?;
+        db.put(&big_key, &big_value)?;
+        db.put(b"z", &big_value)?;
+        db.force_flush()?;
+        assert_eq!(db.get(&big_key)?.as_deref(), Some(&big_value[..]));
+        db.close()?;
+        drop(db);
+
+        let db = MiniLsm::open(dir.path(), config())?;
+        assert_eq!(db.get(b"a")?.as_deref(), Some(&b"small"[..]));
+        assert_eq!(db.get(&big_key)?.as_deref(), Some(&big_value[..]));
+        assert_eq!(db.get(b"z")?.as_deref(), Some(&big_value[..]));
+        let mut iter = db.scan(Bound::Unbounded, Bound::Unbounded)?;
+        let mut keys = Vec::new();
+        while iter.is_valid() {
+            keys.push(iter.key().to_vec());
+            iter.next()?;
+        }
+        assert_eq!(keys, vec![b"a".to_vec(), big_key, b"z".to_vec()]);
+        Ok(())
+    }
+
+    fn synced_file(path: &str) -> Synced {
+        Synced::File(PathBuf::from(path))
+    }
+
+    fn synced_dir(path: &str) -> Synced {
+        Synced::Dir(PathBuf::from(path))
+    }
+
+    fn no_compaction() -> LsmStorageConfig {
+        LsmStorageConfig {
+            enable_wal: true,
+            compaction_option: CompactionOption::NoCompaction,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn new_files_are_synced_with_their_directory() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = MiniLsm::open(dir.path().join("db"), no_compaction())?;
+        assert_eq!(
+            sync_log::take(dir.path()),
+            vec![
+                // the entry of the database directory
+                synced_dir(""),
+                // the manifest, its entry, then its first record
+                synced_file("db/MANIFEST"),
+                synced_dir("db"),
+                synced_file("db/MANIFEST"),
+                // the WAL of the first memtable only after the record of the memtable
+                synced_file("db/0.wal"),
+                synced_dir("db"),
+            ]
+        );
+        db.put(b"a", b"1")?;
+        db.sync()?;
+        assert_eq!(sync_log::take(dir.path()), vec![synced_file("db/0.wal")]);
+        Ok(())
+    }
+
+    #[test]
+    fn flushed_sst_is_durable_before_the_manifest_points_to_it() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = MiniLsm::open(dir.path().join("db"), no_compaction())?;
+        db.put(b"a", b"1")?;
+        sync_log::take(dir.path());
+        db.force_flush()?;
+        assert_eq!(
+            sync_log::take(dir.path()),
+            vec![
+                // the next memtable is recorded before its WAL is created, and the frozen one is
+                // synced
+                synced_file("db/MANIFEST"),
+                synced_file("db/1.wal"),
+                synced_dir("db"),
+                synced_file("db/0.wal"),
+                // the SsTable and its entry, then the record that points to it
+                synced_file("db/0.sst"),
+                synced_dir("db"),
+                synced_file("db/MANIFEST"),
+                // the removal of the WAL the SsTable replaces
+                synced_dir("db"),
+                // force_flush itself
+                synced_dir("db"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn compacted_sst_is_durable_before_the_manifest_points_to_it() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db_dir = dir.path().join("db");
+        let db = MiniLsm::open(&db_dir, no_compaction())?;
+        db.put(b"a", b"1")?;
+        db.force_flush()?;
+        db.put(b"b", b"1")?;
+        db.force_flush()?;
+        sync_log::take(dir.path());
+        db.force_full_compaction()?;
+        assert_eq!(
+            sync_log::take(dir.path()),
+            vec![
+                synced_file("db/3.sst"),
+                synced_dir("db"),
+                synced_file("db/MANIFEST"),
+                // the removal of the SsTables it replaces
+                synced_dir("db"),
+            ]
+        );
+        db.close()?;
+        drop(db);
+        sync_log::take(dir.path());
+
+        let _db = MiniLsm::open(&db_dir, no_compaction())?;
+        assert_eq!(
+            sync_log::take(dir.path()),
+            vec![
+                // the WAL of the last memtable is empty and removed
+                synced_dir("db"),
+                synced_file("db/MANIFEST"),
+                synced_file("db/4.wal"),
+                synced_dir("db"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn levels_written_with_another_compaction_option_are_reshaped() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let open = |compaction_option| {
+            let config = LsmStorageConfig {
+                compaction_option,
+                ..Default::default()
+            };
+            LsmStorageInner::open(dir.path(), config)
+        };
+        // the L0 SsTables and the levels
+        type Shape = (Vec<usize>, Vec<(usize, Vec<usize>)>);
+        let shape = |inner: &LsmStorageInner| -> Result<Shape> {
+            for i in 0..4 {
+                assert_eq!(inner.get(format!("key{}", i).as_bytes())?.as_deref(), Some(&b"value"[..]));
+            }
+            // the controller works with whatever shape open came up with
+            let snapshot = inner.state.read().clone();
+            inner.compaction_controller.generate_compaction_task(&snapshot);
+            Ok((snapshot.l0_sstables.clone(), snapshot.levels.clone()))
+        };
+
+        // one SsTable in L0 and one in each of three levels, the way another strategy left them
+        let inner = open(CompactionOption::NoCompaction)?;
+        let mut ssts = Vec::new();
+        for i in 0..4 {
+            inner.put(format!("key{}", i).as_bytes(), b"value")?;
+            inner.force_flush()?;
+            ssts.push(inner.state.read().l0_sstables[0]);
+        }
+        let (s0, s1, s2, s3) = (ssts[0], ssts[1], ssts[2], ssts[3]);
+        inner.manifest.as_ref().unwrap().add_record(
+            &inner.state_lock.lock(),
+            ManifestRecord::Compaction {
+                l0_sstables: vec![s3],
+                levels: vec![(1, vec![s2]), (2, vec![s1]), (3, vec![s0])],
+            },
+        )?;
+        drop(inner);
+
+        // the runs that do not fit into the levels go below the SsTables already in L0, the
+        // newest first
+        let inner = open(CompactionOption::NoCompaction)?;
+        assert_eq!(shape(&inner)?, (vec![s3, s2, s1], vec![(1, vec![s0])]));
+        drop(inner);
+        let simple = |max_levels| {
+            CompactionOption::Simple(SimpleLeveledCompactionOption {
+                size_ratio_percent: 200,
+                level0_file_num_compaction_trigger: 2,
+                max_levels,
+            })
+        };
+        let inner = open(simple(2))?;
+        assert_eq!(shape(&inner)?, (vec![s3, s2], vec![(1, vec![s1]), (2, vec![s0])]));
+        drop(inner);
+
+        // with more levels than runs the levels on top stay empty
+        let inner = open(simple(4))?;
+        assert_eq!(
+            shape(&inner)?,
+            (vec![s3], vec![(1, vec![]), (2, vec![s2]), (3, vec![s1]), (4, vec![s0])])
+        );
+        drop(inner);
+
+        // leveled compaction has the same levels, only their target sizes differ
+        let leveled = CompactionOption::Leveled(LeveledCompactionOption {
+            level_size_multiplier: 10,
+            base_level_size_mb: 1,
+            max_levels: 4,
+            level0_file_num_compaction_trigger: 2,
+        });
+        let inner = open(leveled)?;
+        assert_eq!(
+            shape(&inner)?,
+            (vec![s3], vec![(1, vec![]), (2, vec![s2]), (3, vec![s1]), (4, vec![s0])])
+        );
+        drop(inner);
+
+        // every SsTable in L0 is a tier of its own, newer than the levels
+        let tiered = CompactionOption::Tiered(TieredCompactionOption {
+            num_tiers: 3,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+        });
+        let inner = open(tiered)?;
+        assert_eq!(
+            shape(&inner)?,
+            (vec![], vec![(s3, vec![s3]), (s2, vec![s2]), (s1, vec![s1]), (s0, vec![s0])])
+        );
+
+        // a flush adds a tier in front, and the shape is recorded as it is now
+        inner.put(b"key3", b"value")?;
+        inner.force_flush()?;
+        let snapshot = inner.state.read().clone();
+        let s4 = snapshot.levels[0].0;
+        assert_eq!(snapshot.levels[0], (s4, vec![s4]));
+        assert_eq!(snapshot.levels.len(), 5);
+        inner.manifest.as_ref().unwrap().add_record(
+            &inner.state_lock.lock(),
+            ManifestRecord::Compaction {
+                l0_sstables: snapshot.l0_sstables.clone(),
+                levels: snapshot.levels.clone(),
+            },
+        )?;
+        drop(inner);
+        let inner = open(CompactionOption::NoCompaction)?;
+        assert_eq!(shape(&inner)?, (vec![s4, s3, s2, s1], vec![(1, vec![s0])]));
+        Ok(())
+    }
+
+    #[test]
+    fn orphan_files_do_not_block_open() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = || LsmStorageConfig {
+            enable_wal: true,
+            ..Default::default()
+        };
+        let db = MiniLsm::open(dir.path(), config())?;
+        db.put(b"a", b"1")?;
+        db.close()?;
+        drop(db);
+
+        // files with the ids the next open hands out, what a crash between creating a file and
+        // recording it leaves behind
+        let max_id = std::fs::read_dir(dir.path())?
+            .filter_map(|entry| entry.unwrap().path().file_stem()?.to_str()?.parse::<usize>().ok())
+            .max()
+            .unwrap();
+        let orphans = [
+            LsmStorageInner::path_of_wal_static(dir.path(), max_id + 1),
+            LsmStorageInner::path_of_wal_static(dir.path(), max_id + 2),
+            LsmStorageInner::path_of_sst_static(dir.path(), max_id + 3),
+        ];
+        std::fs::write(&orphans[0], b"")?;
+        std::fs::write(&orphans[1], b"not a WAL")?;
+        std::fs::write(&orphans[2], b"not an SsTable")?;
+
+        let db = MiniLsm::open(dir.path(), config())?;
+        // the first id goes to the new memtable right away
+        for orphan in &orphans[1..] {
+            assert!(!orphan.exists(), "{:?} was not removed", orphan);
+        }
+        assert_eq!(db.get(b"a")?.as_deref(), Some(&b"1"[..]));
+        db.put(b"b", b"2")?;
+        db.force_flush()?;
+        db.put(b"c", b"3")?;
+        db.close()?;
+        drop(db);
+
+        let db = MiniLsm::open(dir.path(), config())?;
+        assert_eq!(db.get(b"a")?.as_deref(), Some(&b"1"[..]));
+        assert_eq!(db.get(b"b")?.as_deref(), Some(&b"2"[..]));
+        assert_eq!(db.get(b"c")?.as_deref(), Some(&b"3"[..]));
+        Ok(())
+    }
+}
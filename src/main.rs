@@ -1,5 +1,4 @@
 use std::rc::Rc;
-use std::sync::Arc;
 use bytes::Buf;
 // Send trait allows to move the ownership, Sync trait allows share reference between different threads
 // for example, Arc has Sync trait since Arc<T> itself is a pointer, and it
@@ -1,3 +1,204 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, BufMut};
+use parking_lot::{Mutex, MutexGuard};
+use serde::{Deserialize, Serialize};
+use crate::lsm_storage::{sync_file, sync_parent_dir};
+
+/// The append-only log of every change to the structure of the LSM tree. Replaying it from the
+/// beginning gives back which memtables and SsTables exist and where the SsTables live.
 pub struct Manifest {
+    file: Arc<Mutex<File>>,
+    path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ManifestRecord {
+    /// A new memtable (and its WAL) with this id is created.
+    NewMemtable(usize),
+    /// The memtable with this id is flushed into the SsTable with the same id.
+    Flush(usize),
+    /// A compaction finished, the record holds the L0 and levels right after it was applied.
+    Compaction {
+        l0_sstables: Vec<usize>,
+        levels: Vec<(usize, Vec<usize>)>,
+    },
+}
+
+impl Manifest {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .read(true)
+            .create_new(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("failed to create manifest {:?}", path))?;
+        // every record is synced as it is added, which is useless if the manifest itself can
+        // disappear
+        sync_file(&file, path)?;
+        sync_parent_dir(path)?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Open an existing manifest, returns all the records in the order they were written.
+    pub fn recover(path: impl AsRef<Path>) -> Result<(Self, Vec<ManifestRecord>)> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to recover manifest {:?}", path))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        let mut offset = 0;
+        let mut records = Vec::new();
+        loop {
+            match read_record(&buf[offset..]) {
+                ManifestRecordBytes::Complete { json, len } => {
+                    records.push(serde_json::from_slice::<ManifestRecord>(json)?);
+                    offset += len;
+                }
+                // a torn record was never synced, so the change it describes never became
+                // visible either
+                ManifestRecordBytes::Torn => break,
+                ManifestRecordBytes::Corrupted { len } => {
+                    // the same goes for a record that reached its full length with garbage in
+                    // it, only a bad record followed by good ones is real damage
+                    if has_complete_record(&buf[offset + len..]) {
+                        bail!("manifest {:?} is corrupted, checksum mismatch", path);
+                    }
+                    break;
+                }
+            }
+        }
+        if offset != buf.len() {
+            file.set_len(offset as u64)?;
+        }
+        Ok((
+            Self {
+                file: Arc::new(Mutex::new(file)),
+                path: path.to_path_buf(),
+            },
+            records,
+        ))
+    }
+
+    /// Append a record, the caller must hold the state lock so that the order of the records is
+    /// the same as the order in which the changes are applied to the state.
+    pub fn add_record(&self, _state_lock_observer: &MutexGuard<()>, record: ManifestRecord) -> Result<()> {
+        self.add_record_when_init(record)
+    }
+
+    /// Append a record while the storage is being opened, nobody else can touch the state yet.
+    pub fn add_record_when_init(&self, record: ManifestRecord) -> Result<()> {
+        let mut file = self.file.lock();
+        let json = serde_json::to_vec(&record)?;
+        let mut buf: Vec<u8> = Vec::with_capacity(json.len() + size_of::<u64>() + size_of::<u32>());
+        buf.put_u64(json.len() as u64);
+        buf.put_slice(&json);
+        buf.put_u32(crc32fast::hash(&json));
+        file.write_all(&buf)?;
+        // the record has to be on the disk before the change it describes becomes visible
+        sync_file(&file, &self.path)?;
+        Ok(())
+    }
+}
+
+enum ManifestRecordBytes<'a> {
+    Complete { json: &'a [u8], len: usize },
+    // the buffer ends in the middle of the record
+    Torn,
+    // the record is complete but its checksum does not match
+    Corrupted { len: usize },
+}
+
+// Each record looks like
+// | len (u64) | json of the record | checksum (u32) |
+fn read_record(buf: &[u8]) -> ManifestRecordBytes<'_> {
+    let mut rbuf = buf;
+    if rbuf.remaining() < size_of::<u64>() {
+        return ManifestRecordBytes::Torn;
+    }
+    let json_len = rbuf.get_u64();
+    // compared as u64, a garbage length could overflow the addition in usize
+    if (rbuf.remaining() as u64) < json_len.saturating_add(size_of::<u32>() as u64) {
+        return ManifestRecordBytes::Torn;
+    }
+    let json_len = json_len as usize;
+    let json = &rbuf[..json_len];
+    rbuf.advance(json_len);
+    let len = size_of::<u64>() + json_len + size_of::<u32>();
+    if rbuf.get_u32() != crc32fast::hash(json) {
+        return ManifestRecordBytes::Corrupted { len };
+    }
+    ManifestRecordBytes::Complete { json, len }
+}
+
+fn has_complete_record(mut buf: &[u8]) -> bool {
+    loop {
+        match read_record(buf) {
+            ManifestRecordBytes::Complete { .. } => return true,
+            ManifestRecordBytes::Torn => return false,
+            ManifestRecordBytes::Corrupted { len } => buf = &buf[len..],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use tempfile::tempdir;
+    use super::{Manifest, ManifestRecord};
+
+    fn append(path: &std::path::Path, data: &[u8]) {
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(data).unwrap();
+    }
+
+    #[test]
+    fn garbage_tail_is_a_torn_write() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("MANIFEST");
+        let manifest = Manifest::create(&path).unwrap();
+        manifest.add_record_when_init(ManifestRecord::NewMemtable(0)).unwrap();
+        manifest.add_record_when_init(ManifestRecord::Flush(0)).unwrap();
+        drop(manifest);
+        let synced_len = std::fs::metadata(&path).unwrap().len();
+        // a record with a plausible length and zeros where the json and checksum should be
+        let mut garbage = 16u64.to_be_bytes().to_vec();
+        garbage.extend_from_slice(&[0; 20]);
+        append(&path, &garbage);
+
+        let (manifest, records) = Manifest::recover(&path).unwrap();
+        assert_eq!(records, vec![ManifestRecord::NewMemtable(0), ManifestRecord::Flush(0)]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), synced_len);
+        manifest.add_record_when_init(ManifestRecord::NewMemtable(1)).unwrap();
+        drop(manifest);
+        let (_, records) = Manifest::recover(&path).unwrap();
+        assert_eq!(records.len(), 3);
+    }
+
+    #[test]
+    fn bad_record_before_a_good_one_is_corruption() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("MANIFEST");
+        let manifest = Manifest::create(&path).unwrap();
+        manifest.add_record_when_init(ManifestRecord::NewMemtable(0)).unwrap();
+        manifest.add_record_when_init(ManifestRecord::NewMemtable(1)).unwrap();
+        drop(manifest);
+        let mut data = std::fs::read(&path).unwrap();
+        // inside the json of the first record
+        data[10] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
 
-}
\ No newline at end of file
+        assert!(Manifest::recover(&path).is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use std::ops::Bound;
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::atomic::AtomicUsize;
 use bytes::Bytes;
@@ -8,8 +9,10 @@ use crossbeam_skiplist::SkipMap;
 use crate::wal::Wal;
 use ouroboros::self_referencing;
 use crate::iterator::StorageIterator;
+use crate::key::{KeyBytes, KeySlice, TS_DEFAULT, TS_RANGE_END};
+use crate::table::SsTableBuilder;
 
-fn map_bound(original: Bound<&[u8]>) -> Bound<Bytes> {
+pub(crate) fn map_bound(original: Bound<&[u8]>) -> Bound<Bytes> {
     match original {
         Bound::Included(data) => Bound::Included(Bytes::copy_from_slice(data)),
         Bound::Excluded(data) => Bound::Excluded(Bytes::copy_from_slice(data)),
@@ -17,8 +20,18 @@ fn map_bound(original: Bound<&[u8]>) -> Bound<Bytes> {
     }
 }
 
+/// Turn a bound on timestamped keys into the owned form the skipmap range needs.
+pub(crate) fn map_key_bound(original: Bound<KeySlice>) -> Bound<KeyBytes> {
+    match original {
+        Bound::Included(key) => Bound::Included(key.to_key_bytes()),
+        Bound::Excluded(key) => Bound::Excluded(key.to_key_bytes()),
+        Bound::Unbounded => Bound::Unbounded
+    }
+}
+
 pub struct MemTable {
-    map: Arc<SkipMap<Bytes, Bytes>>,
+    // every version of a key is a separate entry, ordered from the newest to the oldest
+    map: Arc<SkipMap<KeyBytes, Bytes>>,
     id: usize,
     // Arc<AtomicUsize> is the substitute for Arc<Mutex<usize>>
     // since it provide better performance. Also, the reason for using Arc is that we have to share
@@ -38,35 +51,99 @@ impl MemTable {
         }
     }
 
-    pub(crate) fn scan(&self, low_bound: Bound<&[u8]>, upper_bound: Bound<&[u8]>) -> MemTableIterator {
-        let range = (map_bound(low_bound), map_bound(upper_bound));
+    /// Create a memtable whose writes are also appended to a new WAL at `path`.
+    pub fn create_with_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self {
+            id,
+            map: Arc::new(SkipMap::new()),
+            wal: Some(Wal::create(path)?),
+            approximate_size: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Rebuild a memtable that was not flushed before the last shutdown from its WAL.
+    pub fn recover_from_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
+        let map = Arc::new(SkipMap::new());
+        let (wal, size) = Wal::recover(path, &map)?;
+        Ok(Self {
+            id,
+            map,
+            wal: Some(wal),
+            approximate_size: Arc::new(AtomicUsize::new(size)),
+        })
+    }
+
+    pub(crate) fn scan(&self, low_bound: Bound<KeySlice>, upper_bound: Bound<KeySlice>) -> MemTableIterator {
+        let range = (map_key_bound(low_bound), map_key_bound(upper_bound));
         let mut iter = MemTableIteratorBuilder {
             // this is Arc, so it is efficient
             map: self.map.clone(),
             // since iter rely on map, I need to take map as a parameter, and .range just returns
             // an iterator
             iter_builder: |map| map.range(range),
-            item: (Bytes::new(), Bytes::new())
+            item: (KeyBytes::default(), Bytes::new())
         }.build();
         let entry = iter.with_iter_mut(|iter| MemTableIterator::entry_to_item(iter.next()));
         iter.with_mut(|x| *x.item = entry);
         iter
     }
 
-    pub(crate) fn get(self: &Self, key: Bytes) -> Option<Bytes> {
-        self.map.get(&key).map(|pair| pair.value().clone())
+    /// The newest version of `key.key_ref()` that is not newer than `key.ts()`.
+    pub(crate) fn get(&self, key: KeySlice) -> Option<Bytes> {
+        let oldest = KeySlice::from_slice(key.key_ref(), TS_RANGE_END);
+        self.map
+            .range(key.to_key_bytes()..=oldest.to_key_bytes())
+            .next()
+            .map(|pair| pair.value().clone())
     }
 
-    pub(crate) fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
-        let estimated_size = key.len() + value.len();
-        self.map.insert(Bytes::copy_from_slice(key), Bytes::copy_from_slice(value));
+    /// Put every pair of the batch, if the WAL fails none of them is in the memtable.
+    pub(crate) fn put_batch(&self, batch: &[(KeySlice, &[u8])]) -> Result<()> {
+        // the WAL goes first, a write that is visible in the memtable must be recoverable
+        if let Some(wal) = &self.wal {
+            wal.put_batch(batch)?;
+        }
+        let mut estimated_size = 0;
+        for (key, value) in batch {
+            estimated_size += key.key_len() + value.len();
+            self.map.insert(key.to_key_bytes(), Bytes::copy_from_slice(value));
+        }
         self.approximate_size.fetch_add(estimated_size, std::sync::atomic::Ordering::Relaxed);
         Ok(())
     }
 
+    /// Write every key-value pair of the memtable into the builder, the skipmap is already sorted
+    /// so the pairs come out in the order the SsTable needs.
+    pub(crate) fn flush(&self, builder: &mut SsTableBuilder) -> Result<()> {
+        for entry in self.map.iter() {
+            builder.add(entry.key().as_key_slice(), entry.value())?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn sync_wal(&self) -> Result<()> {
+        if let Some(wal) = &self.wal {
+            wal.sync()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// The timestamp of the newest write in this memtable.
+    pub(crate) fn max_ts(&self) -> u64 {
+        self.map.iter().map(|entry| entry.key().ts()).max().unwrap_or(TS_DEFAULT)
+    }
+
     pub(crate) fn approximate_size(&self) -> usize {
         self.approximate_size.load(std::sync::atomic::Ordering::Relaxed)
     }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
 }
 
 // I want to make three points clear here:
@@ -79,25 +156,28 @@ impl MemTable {
 // 3. the reason I use self-reference is that the Rust compiler cannot make sure the skipmap always
 // exist when I try to use the iterator that points to it. (Note we can also use 'a here, but it can
 // become quite complicated)
-type SkipMapRangeIter<'a> = crossbeam_skiplist::map::Range<'a, Bytes, (Bound<Bytes>, Bound<Bytes>), Bytes, Bytes>;
+type SkipMapRangeIter<'a> =
+    crossbeam_skiplist::map::Range<'a, KeyBytes, (Bound<KeyBytes>, Bound<KeyBytes>), KeyBytes, Bytes>;
 
 #[self_referencing]
 pub struct MemTableIterator {
-    map: Arc<SkipMap<Bytes, Bytes>>,
+    map: Arc<SkipMap<KeyBytes, Bytes>>,
     #[borrows(map)]
     #[not_covariant]
     iter: SkipMapRangeIter<'this>,
-    item: (Bytes, Bytes),
+    item: (KeyBytes, Bytes),
 }
 
 impl MemTableIterator {
-    fn entry_to_item(entry: Option<Entry<Bytes, Bytes>>) -> (Bytes, Bytes) {
-        entry.map(|each| { return (each.key().clone(), each.value().clone()) })
-             .unwrap_or_else(|| (Bytes::from_static(&[]), Bytes::from_static(&[])))
+    fn entry_to_item(entry: Option<Entry<KeyBytes, Bytes>>) -> (KeyBytes, Bytes) {
+        entry.map(|each| (each.key().clone(), each.value().clone()))
+             .unwrap_or_else(|| (KeyBytes::default(), Bytes::from_static(&[])))
     }
 }
 
 impl StorageIterator for MemTableIterator {
+    type KeyType<'a> = KeySlice<'a>;
+
     fn next(&mut self) -> Result<()> {
         let entry = self
             .with_iter_mut(|iter| MemTableIterator::entry_to_item(iter.next()));
@@ -105,8 +185,8 @@ impl StorageIterator for MemTableIterator {
         Ok(())
     }
 
-    fn key(&self) -> &[u8] {
-        &self.borrow_item().0
+    fn key(&self) -> KeySlice<'_> {
+        self.borrow_item().0.as_key_slice()
     }
 
     fn value(&self) -> &[u8] {
@@ -1,3 +1,177 @@
+mod lock;
+mod txn;
+
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Bound;
+use std::sync::Arc;
+use anyhow::Result;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use crate::lsm_iterator::{FusedIterator, LsmIterator};
+use crate::lsm_storage::LsmStorageInner;
+pub(crate) use lock::LockManager;
+pub use txn::{Transaction, TxnError, TxnIterator};
+
+/// The timestamps of the multi-version concurrency control. Every write gets a commit timestamp
+/// larger than all the ones before it, and a reader only sees the versions committed at or before
+/// the timestamp it reads at, so it keeps a consistent view while new writes come in.
 pub struct LsmMvccInner {
+    /// Held while a write picks its commit timestamp and goes into the memtable, so the writes
+    /// become visible in the order of their timestamps.
+    pub(crate) write_lock: Mutex<()>,
+    // the timestamp of the latest write that is fully in the memtable, readers start from here
+    ts: Mutex<u64>,
+    // the read timestamps of the live snapshots and how many snapshots hold each of them, the
+    // lowest one is the watermark
+    readers: Mutex<BTreeMap<u64, usize>>,
+    // the keys written by each commit after the watermark, only kept in serializable mode, a
+    // transaction checks the commits after its read timestamp against the keys it read
+    committed_writes: Mutex<BTreeMap<u64, HashSet<Bytes>>>,
+}
+
+impl LsmMvccInner {
+    /// `initial_ts` is the largest timestamp found in the data on disk.
+    pub fn new(initial_ts: u64) -> Self {
+        Self {
+            write_lock: Mutex::new(()),
+            ts: Mutex::new(initial_ts),
+            readers: Mutex::new(BTreeMap::new()),
+            committed_writes: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn latest_commit_ts(&self) -> u64 {
+        *self.ts.lock()
+    }
+
+    pub fn update_commit_ts(&self, ts: u64) {
+        *self.ts.lock() = ts;
+    }
+
+    /// Take the latest commit timestamp as a read timestamp and keep it registered until
+    /// `release_read_ts` is called with it.
+    pub(crate) fn acquire_read_ts(&self) -> u64 {
+        // the commit timestamp cannot move on before the reader is registered, otherwise a
+        // compaction could see no reader and drop what the reader needs in between
+        let ts = self.ts.lock();
+        *self.readers.lock().entry(*ts).or_default() += 1;
+        *ts
+    }
+
+    /// The lowest timestamp anyone may still read at. Of the versions of a key committed at or
+    /// before the watermark only the newest one can ever be read again, the older ones can be
+    /// dropped.
+    pub fn watermark(&self) -> u64 {
+        // without a snapshot the next reader starts at the latest commit, and a reader that
+        // registers later never reads below it
+        let ts = self.ts.lock();
+        self.readers.lock().keys().next().copied().unwrap_or(*ts)
+    }
+
+    /// Remember the keys written at `commit_ts` for the transactions that are still running.
+    pub(crate) fn record_commit<'a>(&self, commit_ts: u64, keys: impl Iterator<Item = &'a [u8]>) {
+        // a transaction only checks the commits after its read timestamp, which is never below
+        // the watermark
+        let watermark = self.watermark();
+        let mut committed_writes = self.committed_writes.lock();
+        *committed_writes = committed_writes.split_off(&(watermark + 1));
+        committed_writes.insert(commit_ts, keys.map(Bytes::copy_from_slice).collect());
+    }
+
+    /// Whether a commit after `read_ts` wrote one of `read_set`. The caller holds `write_lock`,
+    /// so nothing commits between the check and its own commit.
+    pub(crate) fn has_conflict(&self, read_ts: u64, read_set: &HashSet<Bytes>) -> bool {
+        self.committed_writes
+            .lock()
+            .range(read_ts + 1..)
+            .any(|(_, keys)| !keys.is_disjoint(read_set))
+    }
+
+    pub(crate) fn release_read_ts(&self, ts: u64) {
+        let mut readers = self.readers.lock();
+        let count = readers.get_mut(&ts).expect("the read timestamp was never acquired");
+        *count -= 1;
+        if *count == 0 {
+            readers.remove(&ts);
+        }
+    }
+}
+
+/// A consistent view of the database as of the moment it was taken. Every read through the
+/// snapshot sees the same data no matter what is written after it, until the snapshot is dropped.
+pub struct Snapshot {
+    inner: Arc<LsmStorageInner>,
+    read_ts: u64,
+}
+
+impl Snapshot {
+    pub(crate) fn new(inner: Arc<LsmStorageInner>) -> Self {
+        let read_ts = inner.mvcc.acquire_read_ts();
+        Self { inner, read_ts }
+    }
+
+    /// The commit timestamp of the latest write the snapshot can see.
+    pub fn read_ts(&self) -> u64 {
+        self.read_ts
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.inner.get_with_ts(key, Some(self.read_ts))
+    }
+
+    pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<FusedIterator<LsmIterator>> {
+        self.inner.scan_with_ts(lower, upper, Some(self.read_ts))
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.inner.mvcc.release_read_ts(self.read_ts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+    use anyhow::Result;
+    use crate::iterator::StorageIterator;
+    use crate::lsm_storage::{LsmStorageConfig, MiniLsm};
+
+    #[test]
+    fn snapshot_keeps_its_view_across_flushes_and_compactions() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = MiniLsm::open(dir.path(), LsmStorageConfig::default())?;
+        db.put(b"a", b"1")?;
+        db.put(b"b", b"1")?;
+        let snapshot = db.snapshot();
+        let check = || -> Result<()> {
+            assert_eq!(snapshot.get(b"a")?.as_deref(), Some(&b"1"[..]));
+            assert_eq!(snapshot.get(b"b")?.as_deref(), Some(&b"1"[..]));
+            assert_eq!(snapshot.get(b"c")?, None);
+            let mut iter = snapshot.scan(Bound::Unbounded, Bound::Unbounded)?;
+            let mut entries = Vec::new();
+            while iter.is_valid() {
+                entries.push((iter.key().to_vec(), iter.value().to_vec()));
+                iter.next()?;
+            }
+            assert_eq!(entries, vec![(b"a".to_vec(), b"1".to_vec()), (b"b".to_vec(), b"1".to_vec())]);
+            Ok(())
+        };
 
-}
\ No newline at end of file
+        db.put(b"a", b"2")?;
+        db.delete(b"b")?;
+        db.put(b"c", b"2")?;
+        check()?;
+        db.force_flush()?;
+        check()?;
+        db.put(b"a", b"3")?;
+        db.force_flush()?;
+        db.force_full_compaction()?;
+        check()?;
+        // the database itself moved on
+        assert_eq!(db.get(b"a")?.as_deref(), Some(&b"3"[..]));
+        assert_eq!(db.get(b"b")?, None);
+        assert_eq!(db.get(b"c")?.as_deref(), Some(&b"2"[..]));
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use parking_lot::{Condvar, Mutex};
+use crate::mvcc::TxnError;
+
+/// The per-key exclusive locks of the pessimistic transactions. A transaction that wants a key
+/// locked by another one waits until the key is released, the lock times out, or waiting would
+/// close a cycle in the wait-for graph.
+pub(crate) struct LockManager {
+    table: Mutex<LockTable>,
+    // notified every time a transaction releases its locks
+    released: Condvar,
+    next_txn_id: AtomicU64,
+}
+
+#[derive(Default)]
+struct LockTable {
+    // the transaction holding the lock of each locked key
+    owners: HashMap<Bytes, u64>,
+    // the keys each transaction holds, so they can all be released at the end
+    held: HashMap<u64, Vec<Bytes>>,
+    // the wait-for graph, a lock has a single owner so a waiting transaction waits for exactly
+    // one other transaction
+    waits_for: HashMap<u64, u64>,
+}
+
+impl LockTable {
+    // Whether following the wait-for edges from `txn_id` leads back to it. Every edge is checked
+    // when it is added, so the graph has no cycle that does not go through the newest edge.
+    fn in_cycle(&self, txn_id: u64) -> bool {
+        let mut current = txn_id;
+        for _ in 0..self.waits_for.len() {
+            match self.waits_for.get(&current) {
+                Some(&next) if next == txn_id => return true,
+                Some(&next) => current = next,
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+impl LockManager {
+    pub fn new() -> Self {
+        Self {
+            table: Mutex::new(LockTable::default()),
+            released: Condvar::new(),
+            next_txn_id: AtomicU64::new(0),
+        }
+    }
+
+    /// The id a pessimistic transaction holds its locks under.
+    pub fn new_txn_id(&self) -> u64 {
+        self.next_txn_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Lock `key` for the transaction, waiting at most `timeout` if another transaction holds
+    /// it. The transaction that would close a cycle of waiting transactions is the victim, it
+    /// gets `TxnError::Deadlock` instead of waiting.
+    pub fn lock(&self, txn_id: u64, key: &[u8], timeout: Duration) -> Result<(), TxnError> {
+        let deadline = Instant::now() + timeout;
+        let mut table = self.table.lock();
+        loop {
+            match table.owners.get(key) {
+                Some(&owner) if owner == txn_id => return Ok(()),
+                // the owner may be different from the last round, a released key can be taken
+                // by another waiter first
+                Some(&owner) => {
+                    table.waits_for.insert(txn_id, owner);
+                    if table.in_cycle(txn_id) {
+                        table.waits_for.remove(&txn_id);
+                        return Err(TxnError::Deadlock);
+                    }
+                    if self.released.wait_until(&mut table, deadline).timed_out()
+                        && table.owners.contains_key(key)
+                    {
+                        table.waits_for.remove(&txn_id);
+                        return Err(TxnError::LockTimeout);
+                    }
+                }
+                None => {
+                    let key = Bytes::copy_from_slice(key);
+                    table.waits_for.remove(&txn_id);
+                    table.owners.insert(key.clone(), txn_id);
+                    table.held.entry(txn_id).or_default().push(key);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn is_waiting(&self, txn_id: u64) -> bool {
+        self.table.lock().waits_for.contains_key(&txn_id)
+    }
+
+    /// Release every lock of the transaction and wake the transactions waiting for them up.
+    pub fn release_all(&self, txn_id: u64) {
+        let mut table = self.table.lock();
+        if let Some(keys) = table.held.remove(&txn_id) {
+            for key in keys {
+                table.owners.remove(&key);
+            }
+            self.released.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use super::LockManager;
+    use crate::mvcc::TxnError;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    // Block until `txn_id` is waiting for a lock, so the next lock call sees the edge.
+    fn wait_until_waiting(manager: &LockManager, txn_id: u64) {
+        let deadline = Instant::now() + WAIT;
+        while !manager.is_waiting(txn_id) {
+            assert!(Instant::now() < deadline, "transaction {} never started waiting", txn_id);
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn lock_in_background(
+        manager: &Arc<LockManager>,
+        txn_id: u64,
+        key: &'static [u8],
+    ) -> thread::JoinHandle<Result<(), TxnError>> {
+        let handle = {
+            let manager = manager.clone();
+            thread::spawn(move || manager.lock(txn_id, key, WAIT))
+        };
+        wait_until_waiting(manager, txn_id);
+        handle
+    }
+
+    #[test]
+    fn lock_is_exclusive_and_reentrant() {
+        let manager = LockManager::new();
+        manager.lock(1, b"a", WAIT).unwrap();
+        manager.lock(1, b"a", WAIT).unwrap();
+        let start = Instant::now();
+        assert_eq!(manager.lock(2, b"a", Duration::from_millis(50)), Err(TxnError::LockTimeout));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        // other keys are not affected
+        manager.lock(2, b"b", WAIT).unwrap();
+    }
+
+    #[test]
+    fn release_wakes_the_waiter_up() {
+        let manager = Arc::new(LockManager::new());
+        manager.lock(1, b"a", WAIT).unwrap();
+        let waiter = lock_in_background(&manager, 2, b"a");
+        manager.release_all(1);
+        assert_eq!(waiter.join().unwrap(), Ok(()));
+        // the waiter owns the key now
+        assert_eq!(manager.lock(1, b"a", Duration::from_millis(10)), Err(TxnError::LockTimeout));
+    }
+
+    #[test]
+    fn two_way_deadlock_picks_the_requester() {
+        let manager = Arc::new(LockManager::new());
+        manager.lock(1, b"a", WAIT).unwrap();
+        manager.lock(2, b"b", WAIT).unwrap();
+        let waiter = lock_in_background(&manager, 1, b"b");
+        let start = Instant::now();
+        assert_eq!(manager.lock(2, b"a", WAIT), Err(TxnError::Deadlock));
+        // found right away instead of after the timeout
+        assert!(start.elapsed() < WAIT);
+        // the victim rolls back and the other one goes on
+        manager.release_all(2);
+        assert_eq!(waiter.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn three_way_deadlock_picks_the_requester() {
+        let manager = Arc::new(LockManager::new());
+        manager.lock(1, b"a", WAIT).unwrap();
+        manager.lock(2, b"b", WAIT).unwrap();
+        manager.lock(3, b"c", WAIT).unwrap();
+        let waiter_1 = lock_in_background(&manager, 1, b"b");
+        let waiter_2 = lock_in_background(&manager, 2, b"c");
+        assert_eq!(manager.lock(3, b"a", WAIT), Err(TxnError::Deadlock));
+        manager.release_all(3);
+        assert_eq!(waiter_2.join().unwrap(), Ok(()));
+        manager.release_all(2);
+        assert_eq!(waiter_1.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn timed_out_wait_is_not_a_deadlock_later() {
+        let manager = LockManager::new();
+        manager.lock(1, b"a", WAIT).unwrap();
+        assert_eq!(manager.lock(2, b"a", Duration::from_millis(10)), Err(TxnError::LockTimeout));
+        manager.lock(2, b"b", WAIT).unwrap();
+        // 2 does not wait for 1 anymore, so 1 waiting for 2 closes no cycle
+        assert_eq!(manager.lock(1, b"b", Duration::from_millis(10)), Err(TxnError::LockTimeout));
+    }
+}
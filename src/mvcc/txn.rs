@@ -0,0 +1,649 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::Bound;
+use std::sync::Arc;
+use anyhow::Result;
+use bytes::Bytes;
+use crossbeam_skiplist::map::Entry;
+use crossbeam_skiplist::SkipMap;
+use ouroboros::self_referencing;
+use parking_lot::Mutex;
+use crate::iterator::StorageIterator;
+use crate::iterator::two_merge_iterator::TwoMergeIterator;
+use crate::lsm_iterator::{FusedIterator, LsmIterator};
+use crate::lsm_storage::{LsmStorageInner, WriteBatchRecord};
+use crate::mem_table::map_bound;
+
+/// Why a transaction could not go on, callers can tell it from an I/O error with `downcast_ref`
+/// and retry the transaction after a conflict, a lock timeout or a deadlock.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxnError {
+    /// Another commit after the transaction started wrote a key the transaction read.
+    Conflict,
+    /// The transaction was used after it committed, or after it was rolled back.
+    Inactive,
+    /// Another transaction held the lock of a key for longer than `lock_timeout`.
+    LockTimeout,
+    /// Waiting for the lock of a key would have been a deadlock, the transaction was rolled back
+    /// to break it.
+    Deadlock,
+}
+
+impl fmt::Display for TxnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TxnError::Conflict => {
+                write!(f, "the transaction read a key that was written after it started")
+            }
+            TxnError::Inactive => write!(f, "the transaction is already committed or rolled back"),
+            TxnError::LockTimeout => write!(f, "timed out waiting for the lock of a key"),
+            TxnError::Deadlock => write!(f, "the transaction was rolled back to break a deadlock"),
+        }
+    }
+}
+
+impl std::error::Error for TxnError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TxnState {
+    Active,
+    Committed,
+    RolledBack,
+    // rolled back as the victim of a deadlock, every later call returns `TxnError::Deadlock`
+    Aborted,
+}
+
+/// Reads see the database as of the moment the transaction started plus the writes of the
+/// transaction itself, the writes are only visible to others after `commit`, all at once.
+///
+/// An optimistic transaction finds out about conflicting writes at `commit` (in serializable
+/// mode), a pessimistic one locks the keys it writes or reads with `get_for_update` as well, and
+/// holds the locks until it commits or rolls back. The locks only keep other pessimistic
+/// transactions away, plain writes and optimistic transactions never wait for them, so in
+/// serializable mode a pessimistic transaction checks what it read with `get` and `scan` at
+/// `commit` like an optimistic one. A pessimistic transaction picked as the victim of a deadlock
+/// is rolled back, and every later call on it returns `TxnError::Deadlock`.
+pub struct Transaction {
+    inner: Arc<LsmStorageInner>,
+    read_ts: u64,
+    // the writes of the transaction, an empty value is a delete, the keys are the write set
+    local_storage: Arc<SkipMap<Bytes, Bytes>>,
+    state: Mutex<TxnState>,
+    // the keys the transaction read from its snapshot, only tracked in serializable mode
+    read_set: Option<Mutex<HashSet<Bytes>>>,
+    // the id the locks of a pessimistic transaction are held under, None for an optimistic one
+    lock_id: Option<u64>,
+}
+
+impl Transaction {
+    pub(crate) fn new(inner: Arc<LsmStorageInner>, pessimistic: bool) -> Arc<Self> {
+        let read_ts = inner.mvcc.acquire_read_ts();
+        // plain writes do not wait for the locks of a pessimistic transaction, so its reads are
+        // checked as well
+        let read_set = inner.config.serializable.then(|| Mutex::new(HashSet::new()));
+        let lock_id = pessimistic.then(|| inner.lock_manager.new_txn_id());
+        Arc::new(Self {
+            inner,
+            read_ts,
+            local_storage: Arc::new(SkipMap::new()),
+            state: Mutex::new(TxnState::Active),
+            read_set,
+            lock_id,
+        })
+    }
+
+    /// The commit timestamp of the latest write the transaction can see from others.
+    pub fn read_ts(&self) -> u64 {
+        self.read_ts
+    }
+
+    // The victim of a deadlock is rolled back behind the caller's back and only finds out here.
+    fn check_active(state: TxnState) -> Result<()> {
+        match state {
+            TxnState::Active => Ok(()),
+            TxnState::Aborted => Err(TxnError::Deadlock.into()),
+            TxnState::Committed | TxnState::RolledBack => Err(TxnError::Inactive.into()),
+        }
+    }
+
+    fn ensure_active(&self) -> Result<()> {
+        Self::check_active(*self.state.lock())
+    }
+
+    // Only a pessimistic transaction takes locks. The victim of a deadlock rolls back right
+    // away, so the transactions it blocks can go on.
+    fn lock(&self, key: &[u8]) -> Result<()> {
+        let Some(lock_id) = self.lock_id else {
+            return Ok(());
+        };
+        match self.inner.lock_manager.lock(lock_id, key, self.inner.config.lock_timeout) {
+            Ok(()) => Ok(()),
+            Err(TxnError::Deadlock) => {
+                self.finish(TxnState::Aborted);
+                Err(TxnError::Deadlock.into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Throw the writes away and release the locks, unless the transaction is already over.
+    fn finish(&self, new_state: TxnState) {
+        let mut state = self.state.lock();
+        if *state == TxnState::Active {
+            *state = new_state;
+            self.local_storage.clear();
+            self.release_locks();
+        }
+    }
+
+    fn release_locks(&self) {
+        if let Some(lock_id) = self.lock_id {
+            self.inner.lock_manager.release_all(lock_id);
+        }
+    }
+
+    fn add_to_read_set(&self, key: &[u8]) {
+        if let Some(read_set) = &self.read_set {
+            read_set.lock().insert(Bytes::copy_from_slice(key));
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.ensure_active()?;
+        if let Some(entry) = self.local_storage.get(key) {
+            let value = entry.value();
+            return Ok((!value.is_empty()).then(|| value.clone()));
+        }
+        self.add_to_read_set(key);
+        self.inner.get_with_ts(key, Some(self.read_ts))
+    }
+
+    /// Lock the key and read its latest committed value, which can be newer than what `get`
+    /// sees. No other pessimistic transaction can write the key until this one ends, but plain
+    /// writes and optimistic transactions still can, and the value read here is not checked at
+    /// `commit`. In an optimistic transaction this is the same as `get`.
+    pub fn get_for_update(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        if self.lock_id.is_none() {
+            return self.get(key);
+        }
+        self.ensure_active()?;
+        self.lock(key)?;
+        if let Some(entry) = self.local_storage.get(key) {
+            let value = entry.value();
+            return Ok((!value.is_empty()).then(|| value.clone()));
+        }
+        self.inner.get_with_ts(key, None)
+    }
+
+    /// Buffer the write until the commit, a pessimistic transaction locks the key first.
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.ensure_active()?;
+        assert!(!key.is_empty(), "key cannot be empty");
+        assert!(!value.is_empty(), "value cannot be empty");
+        self.lock(key)?;
+        self.local_storage.insert(Bytes::copy_from_slice(key), Bytes::copy_from_slice(value));
+        Ok(())
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.ensure_active()?;
+        assert!(!key.is_empty(), "key cannot be empty");
+        self.lock(key)?;
+        self.local_storage.insert(Bytes::copy_from_slice(key), Bytes::new());
+        Ok(())
+    }
+
+    /// Iterate over the keys in the range as the transaction sees them. Every key the iterator
+    /// goes over counts as read, a key inserted into the range by someone else after the
+    /// transaction started is not detected as a conflict.
+    pub fn scan(self: &Arc<Self>, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<TxnIterator> {
+        self.ensure_active()?;
+        let local_iter = TxnLocalIterator::create(self.local_storage.clone(), lower, upper);
+        let storage_iter = self.inner.scan_with_ts(lower, upper, Some(self.read_ts))?;
+        TxnIterator::create(self.clone(), TwoMergeIterator::create(local_iter, storage_iter)?)
+    }
+
+    /// Make every write of the transaction visible with a single commit timestamp. In
+    /// serializable mode this fails with `TxnError::Conflict` and writes nothing if a key the
+    /// transaction read was written by a commit after the transaction started. Either way the
+    /// transaction is over, a failed commit leaves it rolled back. The locks of a pessimistic
+    /// transaction are released once its writes are visible.
+    pub fn commit(&self) -> Result<()> {
+        let size = {
+            // held until the end, the transaction is not active anymore but not committed yet
+            let mut state = self.state.lock();
+            Self::check_active(*state)?;
+            let result = self.write_local_storage();
+            if result.is_ok() {
+                *state = TxnState::Committed;
+            } else {
+                *state = TxnState::RolledBack;
+                self.local_storage.clear();
+            }
+            self.release_locks();
+            result?
+        };
+        // the memtable is frozen without holding the locks
+        self.inner.try_freeze_memtable(size)
+    }
+
+    /// Throw the writes of the transaction away and release its locks. Nothing happens if the
+    /// transaction is already committed or rolled back.
+    pub fn rollback(&self) {
+        self.finish(TxnState::RolledBack);
+    }
+
+    // Returns the size of the memtable after the write.
+    fn write_local_storage(&self) -> Result<usize> {
+        let batch = self
+            .local_storage
+            .iter()
+            .map(|entry| {
+                if entry.value().is_empty() {
+                    WriteBatchRecord::Del(entry.key().clone())
+                } else {
+                    WriteBatchRecord::Put(entry.key().clone(), entry.value().clone())
+                }
+            })
+            .collect::<Vec<_>>();
+        // a read-only transaction is serializable at its read timestamp
+        if batch.is_empty() {
+            return Ok(0);
+        }
+        let write_lock = self.inner.mvcc.write_lock.lock();
+        if let Some(read_set) = &self.read_set
+            && self.inner.mvcc.has_conflict(self.read_ts, &read_set.lock())
+        {
+            return Err(TxnError::Conflict.into());
+        }
+        Ok(self.inner.write_batch_locked(&write_lock, &batch)?.1)
+    }
+}
+
+impl Drop for Transaction {
+    // a transaction that is neither committed nor rolled back is rolled back
+    fn drop(&mut self) {
+        self.rollback();
+        self.inner.mvcc.release_read_ts(self.read_ts);
+    }
+}
+
+type SkipMapRangeIter<'a> =
+    crossbeam_skiplist::map::Range<'a, Bytes, (Bound<Bytes>, Bound<Bytes>), Bytes, Bytes>;
+
+// The same as `MemTableIterator`, but over the writes of a transaction, which have no timestamp.
+#[self_referencing]
+pub struct TxnLocalIterator {
+    map: Arc<SkipMap<Bytes, Bytes>>,
+    #[borrows(map)]
+    #[not_covariant]
+    iter: SkipMapRangeIter<'this>,
+    item: (Bytes, Bytes),
+}
+
+impl TxnLocalIterator {
+    fn create(map: Arc<SkipMap<Bytes, Bytes>>, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Self {
+        let range = (map_bound(lower), map_bound(upper));
+        let mut iter = TxnLocalIteratorBuilder {
+            map,
+            iter_builder: |map| map.range(range),
+            item: (Bytes::new(), Bytes::new()),
+        }
+        .build();
+        let entry = iter.with_iter_mut(|iter| Self::entry_to_item(iter.next()));
+        iter.with_mut(|x| *x.item = entry);
+        iter
+    }
+
+    fn entry_to_item(entry: Option<Entry<Bytes, Bytes>>) -> (Bytes, Bytes) {
+        entry
+            .map(|each| (each.key().clone(), each.value().clone()))
+            .unwrap_or_else(|| (Bytes::new(), Bytes::new()))
+    }
+}
+
+impl StorageIterator for TxnLocalIterator {
+    type KeyType<'a> = &'a [u8];
+
+    fn next(&mut self) -> Result<()> {
+        let entry = self.with_iter_mut(|iter| Self::entry_to_item(iter.next()));
+        self.with_item_mut(|item| *item = entry);
+        Ok(())
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.borrow_item().0
+    }
+
+    // an empty value is a delete of the transaction, `TxnIterator` skips it
+    fn value(&self) -> &[u8] {
+        &self.borrow_item().1
+    }
+
+    fn is_valid(&self) -> bool {
+        !self.borrow_item().0.is_empty()
+    }
+}
+
+/// The result of `Transaction::scan`, the writes of the transaction win over what is in the
+/// database.
+pub struct TxnIterator {
+    txn: Arc<Transaction>,
+    iter: TwoMergeIterator<TxnLocalIterator, FusedIterator<LsmIterator>>,
+}
+
+impl TxnIterator {
+    fn create(
+        txn: Arc<Transaction>,
+        iter: TwoMergeIterator<TxnLocalIterator, FusedIterator<LsmIterator>>,
+    ) -> Result<Self> {
+        let mut iter = Self { txn, iter };
+        iter.move_to_key()?;
+        Ok(iter)
+    }
+
+    // skip the deletes of the transaction, the database side never yields a tombstone
+    fn move_to_key(&mut self) -> Result<()> {
+        while self.iter.is_valid() && self.iter.value().is_empty() {
+            self.iter.next()?;
+        }
+        if self.iter.is_valid() {
+            self.txn.add_to_read_set(self.iter.key());
+        }
+        Ok(())
+    }
+}
+
+impl StorageIterator for TxnIterator {
+    type KeyType<'a> = &'a [u8];
+
+    fn next(&mut self) -> Result<()> {
+        self.iter.next()?;
+        self.move_to_key()
+    }
+
+    fn key(&self) -> &[u8] {
+        self.iter.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.iter.is_valid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use anyhow::Result;
+    use tempfile::TempDir;
+    use super::{Transaction, TxnError};
+    use crate::iterator::StorageIterator;
+    use crate::lsm_storage::{LsmStorageConfig, MiniLsm};
+
+    fn open(serializable: bool) -> Result<(TempDir, Arc<MiniLsm>)> {
+        let dir = tempfile::tempdir()?;
+        let db = MiniLsm::open(
+            dir.path(),
+            LsmStorageConfig {
+                serializable,
+                lock_timeout: Duration::from_millis(100),
+                ..Default::default()
+            },
+        )?;
+        Ok((dir, db))
+    }
+
+    fn is_txn_error<T>(result: Result<T>, expected: TxnError) -> bool {
+        result.is_err_and(|e| e.downcast_ref::<TxnError>() == Some(&expected))
+    }
+
+    fn is_conflict(result: Result<()>) -> bool {
+        is_txn_error(result, TxnError::Conflict)
+    }
+
+    fn scan_all(txn: &Arc<Transaction>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut iter = txn.scan(Bound::Unbounded, Bound::Unbounded)?;
+        let mut entries = Vec::new();
+        while iter.is_valid() {
+            entries.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.next()?;
+        }
+        Ok(entries)
+    }
+
+    #[test]
+    fn write_skew_aborts_one_side() -> Result<()> {
+        let (_dir, db) = open(true)?;
+        db.put(b"x", b"1")?;
+        db.put(b"y", b"1")?;
+        // each one reads the key the other one writes
+        let t1 = db.new_txn();
+        let t2 = db.new_txn();
+        t1.get(b"x")?;
+        t1.put(b"y", b"0")?;
+        t2.get(b"y")?;
+        t2.put(b"x", b"0")?;
+        t1.commit()?;
+        assert!(is_conflict(t2.commit()));
+        // nothing of the aborted side is written
+        assert_eq!(db.get(b"x")?.as_deref(), Some(&b"1"[..]));
+        assert_eq!(db.get(b"y")?.as_deref(), Some(&b"0"[..]));
+        Ok(())
+    }
+
+    #[test]
+    fn conflicts_are_only_detected_when_serializable() -> Result<()> {
+        for serializable in [false, true] {
+            let (_dir, db) = open(serializable)?;
+            db.put(b"x", b"1")?;
+            let txn = db.new_txn();
+            txn.get(b"x")?;
+            txn.put(b"y", b"1")?;
+            // a plain write is a commit as well
+            db.put(b"x", b"2")?;
+            assert_eq!(is_conflict(txn.commit()), serializable);
+            assert_eq!(db.get(b"y")?.is_some(), !serializable);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_transaction_commits() -> Result<()> {
+        let (_dir, db) = open(true)?;
+        db.put(b"x", b"1")?;
+        let txn = db.new_txn();
+        assert_eq!(txn.get(b"x")?.as_deref(), Some(&b"1"[..]));
+        db.put(b"x", b"2")?;
+        // it still sees the value it started with
+        assert_eq!(txn.get(b"x")?.as_deref(), Some(&b"1"[..]));
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn keys_read_by_a_scan_conflict() -> Result<()> {
+        let (_dir, db) = open(true)?;
+        db.put(b"a", b"1")?;
+        db.put(b"b", b"1")?;
+        let txn = db.new_txn();
+        scan_all(&txn)?;
+        txn.put(b"c", b"1")?;
+        db.put(b"b", b"2")?;
+        assert!(is_conflict(txn.commit()));
+        Ok(())
+    }
+
+    #[test]
+    fn commits_before_the_watermark_are_forgotten() -> Result<()> {
+        let (_dir, db) = open(true)?;
+        let txn = db.new_txn();
+        txn.get(b"x")?;
+        txn.put(b"w", b"0")?;
+        db.put(b"x", b"1")?;
+        db.put(b"y", b"1")?;
+        // the open transaction keeps both commits after its read timestamp
+        assert_eq!(db.inner.mvcc.committed_writes.lock().len(), 2);
+        assert!(is_conflict(txn.commit()));
+        drop(txn);
+        // without readers the next commit drops everything up to the latest one
+        db.put(b"z", b"1")?;
+        assert_eq!(db.inner.mvcc.committed_writes.lock().len(), 1);
+        // and a transaction started after a commit never conflicts with it
+        let txn = db.new_txn();
+        txn.get(b"z")?;
+        txn.put(b"w", b"1")?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn scan_overlays_the_writes_of_the_transaction() -> Result<()> {
+        let (_dir, db) = open(false)?;
+        for key in [b"a", b"b", b"c", b"d"] {
+            db.put(key, b"db")?;
+        }
+        let txn = db.new_txn();
+        txn.put(b"b", b"txn")?;
+        txn.delete(b"c")?;
+        txn.put(b"e", b"txn")?;
+        txn.delete(b"f")?;
+        let entry = |key: &[u8], value: &[u8]| (key.to_vec(), value.to_vec());
+        assert_eq!(
+            scan_all(&txn)?,
+            vec![entry(b"a", b"db"), entry(b"b", b"txn"), entry(b"d", b"db"), entry(b"e", b"txn")]
+        );
+        let mut iter = txn.scan(Bound::Excluded(b"a"), Bound::Included(b"d"))?;
+        let mut keys = Vec::new();
+        while iter.is_valid() {
+            keys.push(iter.key().to_vec());
+            iter.next()?;
+        }
+        assert_eq!(keys, vec![b"b".to_vec(), b"d".to_vec()]);
+        // others do not see any of it until the commit
+        assert_eq!(db.get(b"c")?.as_deref(), Some(&b"db"[..]));
+        txn.commit()?;
+        assert_eq!(db.get(b"c")?, None);
+        assert_eq!(db.get(b"e")?.as_deref(), Some(&b"txn"[..]));
+        Ok(())
+    }
+
+    #[test]
+    fn transaction_cannot_be_used_once_it_is_over() -> Result<()> {
+        let (_dir, db) = open(true)?;
+        let committed = db.new_txn();
+        committed.put(b"x", b"1")?;
+        committed.commit()?;
+        // a transaction whose commit failed is rolled back, it does not get a second try
+        let conflicted = db.new_txn();
+        conflicted.get(b"x")?;
+        conflicted.put(b"y", b"1")?;
+        db.put(b"x", b"2")?;
+        assert!(is_conflict(conflicted.commit()));
+        for txn in [&committed, &conflicted] {
+            assert!(is_txn_error(txn.get(b"x"), TxnError::Inactive));
+            assert!(is_txn_error(txn.put(b"x", b"3"), TxnError::Inactive));
+            assert!(is_txn_error(txn.delete(b"x"), TxnError::Inactive));
+            assert!(is_txn_error(txn.scan(Bound::Unbounded, Bound::Unbounded), TxnError::Inactive));
+            assert!(is_txn_error(txn.commit(), TxnError::Inactive));
+        }
+        assert_eq!(db.get(b"x")?.as_deref(), Some(&b"2"[..]));
+        assert_eq!(db.get(b"y")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn locks_are_released_when_the_transaction_ends() -> Result<()> {
+        let (_dir, db) = open(false)?;
+        let ends: [fn(Arc<Transaction>) -> Result<()>; 3] = [
+            |txn| txn.commit(),
+            |txn| {
+                txn.rollback();
+                Ok(())
+            },
+            |txn| {
+                drop(txn);
+                Ok(())
+            },
+        ];
+        for end in ends {
+            let holder = db.new_pessimistic_txn();
+            holder.put(b"a", b"1")?;
+            let other = db.new_pessimistic_txn();
+            assert!(is_txn_error(other.put(b"a", b"2"), TxnError::LockTimeout));
+            assert!(is_txn_error(other.get_for_update(b"a"), TxnError::LockTimeout));
+            end(holder)?;
+            // a timeout does not end the transaction
+            other.put(b"a", b"2")?;
+            other.commit()?;
+            assert_eq!(db.get(b"a")?.as_deref(), Some(&b"2"[..]));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn plain_writes_do_not_wait_for_locks_and_conflict_with_reads() -> Result<()> {
+        let (_dir, db) = open(true)?;
+        db.put(b"x", b"1")?;
+        let txn = db.new_pessimistic_txn();
+        assert_eq!(txn.get_for_update(b"x")?.as_deref(), Some(&b"1"[..]));
+        assert_eq!(txn.get(b"y")?, None);
+        txn.put(b"z", b"1")?;
+        // neither write waits for the lock of x
+        db.put(b"x", b"2")?;
+        db.put(b"y", b"2")?;
+        // the read of y is checked like in an optimistic transaction
+        assert!(is_conflict(txn.commit()));
+        assert_eq!(db.get(b"z")?, None);
+
+        // and a pessimistic transaction that only locked x does not conflict
+        let txn = db.new_pessimistic_txn();
+        txn.get_for_update(b"x")?;
+        db.put(b"x", b"3")?;
+        txn.put(b"z", b"1")?;
+        txn.commit()?;
+        assert_eq!(db.get(b"z")?.as_deref(), Some(&b"1"[..]));
+        Ok(())
+    }
+
+    #[test]
+    fn deadlock_victim_returns_errors_instead_of_panicking() -> Result<()> {
+        let (_dir, db) = open(false)?;
+        let t1 = db.new_pessimistic_txn();
+        let t2 = db.new_pessimistic_txn();
+        t1.put(b"a", b"t1")?;
+        t2.put(b"b", b"t2")?;
+        let waiter = {
+            let t1 = t1.clone();
+            thread::spawn(move || -> Result<()> {
+                t1.put(b"b", b"t1")?;
+                t1.commit()
+            })
+        };
+        // wait until t1 is blocked on the lock of b
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !db.inner.lock_manager.is_waiting(t1.lock_id.unwrap()) {
+            assert!(Instant::now() < deadline, "t1 never started waiting");
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(is_txn_error(t2.put(b"a", b"t2"), TxnError::Deadlock));
+        // t2 is rolled back, which lets t1 finish
+        waiter.join().unwrap()?;
+        assert!(is_txn_error(t2.get(b"a"), TxnError::Deadlock));
+        assert!(is_txn_error(t2.get_for_update(b"a"), TxnError::Deadlock));
+        assert!(is_txn_error(t2.put(b"c", b"t2"), TxnError::Deadlock));
+        assert!(is_txn_error(t2.delete(b"c"), TxnError::Deadlock));
+        assert!(is_txn_error(t2.scan(Bound::Unbounded, Bound::Unbounded), TxnError::Deadlock));
+        assert!(is_txn_error(t2.commit(), TxnError::Deadlock));
+        t2.rollback();
+        assert_eq!(db.get(b"a")?.as_deref(), Some(&b"t1"[..]));
+        assert_eq!(db.get(b"b")?.as_deref(), Some(&b"t1"[..]));
+        Ok(())
+    }
+}
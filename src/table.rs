@@ -1,20 +1,30 @@
+pub(crate) mod bloom;
 mod builder;
+pub mod compress;
 mod iterator;
+mod properties;
 
+pub use builder::SsTableBuilder;
+pub use iterator::SsTableIterator;
+pub use properties::TableProperties;
+
+use std::fmt;
 use std::fs::File;
-use std::io::Read;
 use std::os::unix::fs::FileExt;
 use std::path::Path;
 use std::sync::Arc;
 use bytes::{Buf, BufMut, Bytes};
 use anyhow::{anyhow, Result};
-use crate::block::Block;
+use crate::block::{Block, BlockIterator};
+use crate::key::{KeyBytes, KeySlice, TS_DEFAULT};
 use crate::lsm_storage::BlockCache;
+use crate::table::bloom::Bloom;
+use crate::table::compress::CompressionType;
 
 pub struct BlockMeta {
     pub offset: usize,
-    pub first_key: Bytes,
-    pub last_key: Bytes,
+    pub first_key: KeyBytes,
+    pub last_key: KeyBytes,
 }
 
 impl BlockMeta {
@@ -25,38 +35,51 @@ impl BlockMeta {
         let mut estimated_size = 0;
         for meta in block_meta {
             // The size of offset
-            estimated_size += size_of::<u32>();
+            estimated_size += size_of::<u64>();
             // The size of key length
-            estimated_size += size_of::<u16>();
-            // The size of actual key
-            estimated_size += meta.first_key.len();
+            estimated_size += size_of::<u32>();
+            // The size of actual key and its timestamp
+            estimated_size += meta.first_key.key_len() + size_of::<u64>();
             // The size of key length
-            estimated_size += size_of::<u16>();
-            // The size of actual key
-            estimated_size += meta.last_key.len();
+            estimated_size += size_of::<u32>();
+            // The size of actual key and its timestamp
+            estimated_size += meta.last_key.key_len() + size_of::<u64>();
         }
         // Reserve the space to improve performance, especially when the size of incoming data is
         // large
         buf.reserve(estimated_size);
         let original_len = buf.len();
         for meta in block_meta {
-            buf.put_u32(meta.offset as u32);
-            buf.put_u16(meta.first_key.len() as u16);
-            buf.put_slice(&meta.first_key);
-            buf.put_u16(meta.last_key.len() as u16);
-            buf.put_slice(&meta.last_key);
+            buf.put_u64(meta.offset as u64);
+            buf.put_u32(meta.first_key.key_len() as u32);
+            buf.put_slice(meta.first_key.key_ref());
+            buf.put_u64(meta.first_key.ts());
+            buf.put_u32(meta.last_key.key_len() as u32);
+            buf.put_slice(meta.last_key.key_ref());
+            buf.put_u64(meta.last_key.ts());
         }
         assert_eq!(estimated_size, buf.len() - original_len);
     }
 
-    pub fn decode_block_meta(mut buf: impl Buf) -> Vec<BlockMeta> {
+    /// Decode the block meta written by `encode_block_meta`, files written before
+    /// `SST_FORMAT_VERSION` 2 store the block offsets as u32, and the keys only have a timestamp
+    /// since version 4.
+    pub fn decode_block_meta(mut buf: impl Buf, version: u32) -> Vec<BlockMeta> {
         let mut block_meta = Vec::new();
+        let get_key = |buf: &mut dyn Buf| {
+            let key_len = buf.get_u32() as usize;
+            let key = buf.copy_to_bytes(key_len);
+            let ts = if version >= 4 { buf.get_u64() } else { TS_DEFAULT };
+            KeyBytes::from_bytes_with_ts(key, ts)
+        };
         while buf.has_remaining() {
-            let offset = buf.get_u32() as usize;
-            let first_key_len = buf.get_u16() as usize;
-            let first_key = buf.copy_to_bytes(first_key_len);
-            let last_key_len = buf.get_u16() as usize;
-            let last_key = buf.copy_to_bytes(last_key_len);
+            let offset = if version >= 2 {
+                buf.get_u64() as usize
+            } else {
+                buf.get_u32() as usize
+            };
+            let first_key = get_key(&mut buf);
+            let last_key = get_key(&mut buf);
             block_meta.push(BlockMeta {
                 offset,
                 first_key,
@@ -67,10 +90,158 @@ impl BlockMeta {
     }
 }
 
+/// The version of the SsTable format written by `SsTableBuilder`.
+/// 1: u32 block offsets, `| meta offset (u32) |` after the block meta, `| bloom offset (u32) |` at the end.
+/// 2: u64 block offsets, a `| meta offset (u64) | bloom offset (u64) | version (u32) |` trailer.
+/// 3: a properties section and the fixed size footer described on `SsTable::open`.
+/// 4: every key in the blocks and the block meta is followed by its timestamp (u64), and the
+///    properties record the largest timestamp.
+pub const SST_FORMAT_VERSION: u32 = 4;
+
+/// The last 8 bytes of every SsTable since version 3, anything else is either an older SsTable or
+/// not a SsTable at all.
+pub const SST_MAGIC: u64 = 0x4d69_6e69_4c53_4d54;
+
+/// | meta offset (u64) | bloom offset (u64) | properties offset (u64) | version (u32) | magic (u64) |
+pub(crate) const SST_FOOTER_SIZE: u64 = 8 * 3 + 4 + 8;
+
+pub(crate) const SIZEOF_CHECKSUM: usize = size_of::<u32>();
+
+/// The error (wrapped in `anyhow::Error`) for a part of a SsTable that does not match the checksum
+/// stored after it, or a footer that does not make sense, so callers can tell a corrupted file
+/// from an I/O error with `downcast_ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SsTableCorruption {
+    Block { sst_id: usize, block_idx: usize },
+    BlockMeta { sst_id: usize },
+    Bloom { sst_id: usize },
+    Properties { sst_id: usize },
+    Footer { sst_id: usize },
+}
+
+impl fmt::Display for SsTableCorruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SsTableCorruption::Block { sst_id, block_idx } => {
+                write!(f, "block {} of SsTable {} is corrupted, checksum mismatch", block_idx, sst_id)
+            }
+            SsTableCorruption::BlockMeta { sst_id } => {
+                write!(f, "block meta of SsTable {} is corrupted, checksum mismatch", sst_id)
+            }
+            SsTableCorruption::Bloom { sst_id } => {
+                write!(f, "bloom filter of SsTable {} is corrupted, checksum mismatch", sst_id)
+            }
+            SsTableCorruption::Properties { sst_id } => {
+                write!(f, "properties of SsTable {} are corrupted, checksum mismatch", sst_id)
+            }
+            SsTableCorruption::Footer { sst_id } => {
+                write!(f, "SsTable {} has an invalid footer, the file is truncated or not a SsTable", sst_id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SsTableCorruption {}
+
+/// Split a section into its content and the checksum that follows it, None if they do not match.
+fn verify_checksum(data: &[u8]) -> Option<&[u8]> {
+    if data.len() < SIZEOF_CHECKSUM {
+        return None;
+    }
+    let (content, mut checksum) = data.split_at(data.len() - SIZEOF_CHECKSUM);
+    if checksum.get_u32() != crc32fast::hash(content) {
+        return None;
+    }
+    Some(content)
+}
+
+// Where the sections of a SsTable are, every range includes the checksum at its end.
+struct Footer {
+    version: u32,
+    block_meta: (u64, u64),
+    bloom: (u64, u64),
+    // None for the files written before the properties section existed
+    properties: Option<(u64, u64)>,
+}
+
+impl Footer {
+    fn read(file_object: &FileObject, id: usize) -> Result<Self> {
+        let len = file_object.size();
+        let invalid = SsTableCorruption::Footer { sst_id: id };
+        if len < 8 {
+            return Err(invalid.into());
+        }
+        let magic = (&file_object.read(len - 8, 8)?[..]).get_u64();
+        let footer = if magic == SST_MAGIC {
+            if len < SST_FOOTER_SIZE {
+                return Err(invalid.into());
+            }
+            let footer_offset = len - SST_FOOTER_SIZE;
+            let raw = file_object.read(footer_offset, SST_FOOTER_SIZE - 8)?;
+            let mut raw = &raw[..];
+            let block_meta_offset = raw.get_u64();
+            let bloom_offset = raw.get_u64();
+            let properties_offset = raw.get_u64();
+            let version = raw.get_u32();
+            if !(3..=SST_FORMAT_VERSION).contains(&version) {
+                return Err(anyhow!("unsupported SsTable format version {} in SsTable {}", version, id));
+            }
+            Footer {
+                version,
+                block_meta: (block_meta_offset, bloom_offset),
+                bloom: (bloom_offset, properties_offset),
+                properties: Some((properties_offset, footer_offset)),
+            }
+        } else {
+            Self::read_legacy(file_object, len).ok_or(invalid.clone())?
+        };
+        // the sections follow each other, so the offsets can at least be checked against each
+        // other before anything gets read with them
+        let sections = [Some(footer.block_meta), Some(footer.bloom), footer.properties];
+        let in_order = sections.iter().flatten().all(|(start, end)| start <= end && *end <= len);
+        if !in_order || footer.block_meta.0 == 0 {
+            return Err(invalid.into());
+        }
+        Ok(footer)
+    }
+
+    // Version 1 and 2 files have no magic and end with a trailer instead.
+    // A version 1 file ends with the bloom offset (u32) instead of the version, the bloom filter
+    // always comes after at least one block and the block meta so that offset can never be
+    // mistaken for a version number.
+    fn read_legacy(file_object: &FileObject, len: u64) -> Option<Self> {
+        let last_u32 = (&file_object.read(len - 4, 4).ok()?[..]).get_u32();
+        if last_u32 == 2 {
+            let trailer_offset = len.checked_sub(4 + 16)?;
+            let trailer = file_object.read(trailer_offset, 16).ok()?;
+            let mut trailer = &trailer[..];
+            let block_meta_offset = trailer.get_u64();
+            let bloom_offset = trailer.get_u64();
+            return Some(Footer {
+                version: 2,
+                block_meta: (block_meta_offset, bloom_offset),
+                bloom: (bloom_offset, trailer_offset),
+                properties: None,
+            });
+        }
+        let bloom_offset = last_u32 as u64;
+        if bloom_offset < 4 || bloom_offset > len - 4 {
+            return None;
+        }
+        let block_meta_offset = (&file_object.read(bloom_offset - 4, 4).ok()?[..]).get_u32() as u64;
+        Some(Footer {
+            version: 1,
+            block_meta: (block_meta_offset, bloom_offset - 4),
+            bloom: (bloom_offset, len - 4),
+            properties: None,
+        })
+    }
+}
+
 pub struct FileObject(Option<File>, u64);
 
 impl FileObject {
-    fn read(&self, offset: u64, len: u32) -> Result<Vec<u8>> {
+    pub fn read(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
         let mut data = vec![0; len as usize];
         self.0
             .as_ref()
@@ -83,18 +254,6 @@ impl FileObject {
         self.1
     }
 
-    pub fn create(path: &Path, data: Vec<u8>) -> Result<Self> {
-        std::fs::write(path, &data)?;
-        File::open(path)?.sync_all()?;
-        Ok(FileObject(
-            Some(File::options()
-                .read(true)
-                .write(false)
-                .open(path)?),
-            data.len() as u64,
-        ))
-    }
-
     pub fn open(path: &Path) -> Result<Self> {
         let file = File::options().read(true).write(false).open(path)?;
         let size = file.metadata()?.len();
@@ -111,30 +270,62 @@ pub struct SsTable {
     block_meta_offset: usize,
     id: usize,
     block_cache: Option<Arc<BlockCache>>,
-    first_key: Bytes,
-    last_key: Bytes,
+    first_key: KeyBytes,
+    last_key: KeyBytes,
+    bloom: Option<Bloom>,
+    properties: Option<TableProperties>,
+    /// The format version the file was written with, which decides how its blocks are decoded.
+    version: u32,
 }
 
 impl SsTable {
-    fn open(file_object: FileObject, block_cache: Option<Arc<BlockCache>>, id: usize) -> Result<Self> {
-        let block_meta_offset_raw = file_object.read(file_object.size() - 4, 4)?;
-        // the reason why I use get_u32 is that it only actually occupies 4 bytes.
-        let block_meta_offset = (&block_meta_offset_raw[..]).get_u32() as u64;
-        let block_metas_raw = file_object.read(block_meta_offset, (file_object.size() - 4 - block_meta_offset) as u32)?;
-        let block_meta = BlockMeta::decode_block_meta(&block_metas_raw[..]);
+    /// The SsTable looks like
+    /// | data blocks | block meta | checksum | bloom filter | checksum | properties | checksum | footer |
+    /// where the footer has a fixed size and looks like
+    /// | meta offset (u64) | bloom offset (u64) | properties offset (u64) | version (u32) | magic (u64) |
+    /// and every data block looks like
+    /// | compression type (u8) | the encoded block after compression | checksum |
+    pub fn open(file_object: FileObject, block_cache: Option<Arc<BlockCache>>, id: usize) -> Result<Self> {
+        let footer = Footer::read(&file_object, id)?;
+        let read_section = |(start, end): (u64, u64), corruption: SsTableCorruption| -> Result<Bytes> {
+            let raw = file_object.read(start, end - start)?;
+            let content = verify_checksum(&raw).ok_or(corruption)?;
+            Ok(Bytes::copy_from_slice(content))
+        };
+        let bloom_raw = read_section(footer.bloom, SsTableCorruption::Bloom { sst_id: id })?;
+        let bloom = if bloom_raw.is_empty() {
+            None
+        } else {
+            Some(Bloom::decode(&bloom_raw)?)
+        };
+        let properties = match footer.properties {
+            Some(range) => Some(TableProperties::decode(
+                &read_section(range, SsTableCorruption::Properties { sst_id: id })?,
+                footer.version,
+            )?),
+            None => None,
+        };
+        let block_metas_raw = read_section(footer.block_meta, SsTableCorruption::BlockMeta { sst_id: id })?;
+        let block_meta = BlockMeta::decode_block_meta(block_metas_raw, footer.version);
+        if block_meta.is_empty() {
+            return Err(SsTableCorruption::Footer { sst_id: id }.into());
+        }
         Ok(Self {
             file: file_object,
             first_key: block_meta.first().unwrap().first_key.clone(),
             last_key: block_meta.last().unwrap().last_key.clone(),
             block_meta,
-            block_meta_offset: block_meta_offset as usize,
+            block_meta_offset: footer.block_meta.0 as usize,
             id,
             block_cache,
+            bloom,
+            properties,
+            version: footer.version,
         })
     }
 
     /// Create a mock SST with only first key + last key metadata
-    pub fn create_meta_only(id: usize, file_size: u64, first_key: Bytes, last_key: Bytes) -> Self {
+    pub fn create_meta_only(id: usize, file_size: u64, first_key: KeyBytes, last_key: KeyBytes) -> Self {
         Self {
             file: FileObject(None, file_size),
             block_meta: vec![],
@@ -143,24 +334,38 @@ impl SsTable {
             block_cache: None,
             first_key,
             last_key,
+            bloom: None,
+            properties: None,
+            version: SST_FORMAT_VERSION,
         }
     }
 
     // the right way to think about this is
     // to get the raw data from the file then decode it
     // to make sure that it does not decode the whole thing and make the whole thing on memory
-    fn read_block(&self, idx: usize) -> Result<Arc<Block>> {
+    pub fn read_block(&self, idx: usize) -> Result<Arc<Block>> {
         // the idx HAVE to be usize
         let offset = self.block_meta[idx].offset;
         let next_block_offset = self.block_meta.get(idx + 1)
             // self.block_meta_offset is the first index of the block meta section
-            .map_or(self.block_meta_offset, |x| x.offset);;
+            .map_or(self.block_meta_offset, |x| x.offset);
         let length = next_block_offset - offset;
-        let block_data = self.file.read(offset as u64, length as u32)?;
-        Ok(Arc::new(Block::decode(&block_data[..])))
+        let block_data = self.file.read(offset as u64, length as u64)?;
+        let block_data = verify_checksum(&block_data)
+            // there is at least the compression type, even for an empty block
+            .filter(|block_data| !block_data.is_empty())
+            .ok_or(SsTableCorruption::Block {
+                sst_id: self.id,
+                block_idx: idx,
+            })?;
+        let compression = CompressionType::from_u8(block_data[0])?;
+        let block_data = compression.compressor().decompress(&block_data[1..])?;
+        // the block is decompressed before it goes into the block cache, so a cache hit costs
+        // nothing extra
+        Ok(Arc::new(Block::decode(&block_data, self.version >= 4)))
     }
 
-    fn read_block_cache(&self, block_idx: usize) -> Result<Arc<Block>> {
+    pub fn read_block_cache(&self, block_idx: usize) -> Result<Arc<Block>> {
         if let Some(block_cache) = self.block_cache.clone() {
             let cached_data = block_cache
                 // the reason it takes in a closure
@@ -170,22 +375,264 @@ impl SsTable {
                 // the Error type .try_get_with() returns is not anyhow::Error type
                 // so we need to use anyhow!() macro to convert it
                 .try_get_with((self.id, block_idx), || self.read_block(block_idx))
-                .map_err( |err| anyhow!("{}", err))?;
+                // keep a corruption distinguishable after it went through the cache
+                .map_err(|err| match err.downcast_ref::<SsTableCorruption>() {
+                    Some(corruption) => anyhow::Error::new(corruption.clone()),
+                    None => anyhow!("{}", err),
+                })?;
             Ok(cached_data)
         } else {
             self.read_block(block_idx)
         }
     }
 
-    fn find_block_idx(&self, key: &[u8]) -> usize {
+    pub fn find_block_idx(&self, key: KeySlice) -> usize {
         // .partition_point() is the binary search method that returns the first one
         // that satisfy the condition
         self.block_meta.partition_point(|meta| {
-            meta.first_key <= key
+            meta.first_key.as_key_slice() <= key
         }).saturating_sub(1)
     }
 
-    fn num_of_blocks(&self) -> usize {
+    /// Whether `key` falls in the key range of this SsTable, if not there is no need to read it.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.first_key.key_ref() <= key && key <= self.last_key.key_ref()
+    }
+
+    /// Look up the newest version of `key.key_ref()` that is not newer than `key.ts()`, the value
+    /// is returned as it is stored, so an empty value is a tombstone and the caller should stop
+    /// looking in older data.
+    pub fn get(&self, key: KeySlice) -> Result<Option<Bytes>> {
+        if !self.may_contain(key.key_ref()) {
+            return Ok(None);
+        }
+        if let Some(bloom) = &self.bloom
+            && !bloom.may_contain(Bloom::hash(key.key_ref()))
+        {
+            return Ok(None);
+        }
+        let block_idx = self.find_block_idx(key);
+        let block = self.read_block_cache(block_idx)?;
+        let mut iter = BlockIterator::create_and_seek_to_key(block, key);
+        // every version in the block may be newer than the one asked for, the next version then
+        // starts the next block
+        if !iter.is_valid() && block_idx + 1 < self.num_of_blocks() {
+            iter = BlockIterator::create_and_seek_to_first(self.read_block_cache(block_idx + 1)?);
+        }
+        if iter.is_valid() && iter.key().key_ref() == key.key_ref() {
+            return Ok(Some(Bytes::copy_from_slice(iter.value())));
+        }
+        Ok(None)
+    }
+
+    pub fn num_of_blocks(&self) -> usize {
         self.block_meta.len()
     }
+
+    pub fn first_key(&self) -> &KeyBytes {
+        &self.first_key
+    }
+
+    pub fn last_key(&self) -> &KeyBytes {
+        &self.last_key
+    }
+
+    /// The timestamp of the newest write in this SsTable, `TS_DEFAULT` for the files written
+    /// before keys were versioned.
+    pub fn max_ts(&self) -> u64 {
+        self.properties.as_ref().map_or(TS_DEFAULT, |properties| properties.max_ts)
+    }
+
+    pub fn table_size(&self) -> u64 {
+        self.file.size()
+    }
+
+    /// The properties recorded when this SsTable was built, None for the files written before
+    /// version 3 of the format.
+    pub fn properties(&self) -> Option<&TableProperties> {
+        self.properties.as_ref()
+    }
+
+    pub fn sst_id(&self) -> usize {
+        self.id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+    use super::{FileObject, SsTable, SsTableBuilder, SsTableCorruption, SsTableIterator, TableProperties};
+    use crate::iterator::StorageIterator;
+    use crate::key::{KeySlice, TS_DEFAULT, TS_RANGE_BEGIN};
+    use crate::table::compress::CompressionType;
+
+    // Written by the builder of each older format version, 100 keys `key_000` to `key_099` with
+    // the values `value_000` to `value_099`, in blocks of 256 bytes. The version 2 one is
+    // compressed with Lz4.
+    #[test]
+    fn old_format_versions_still_open() {
+        for version in 1..=3 {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(format!("tests/fixtures/sst_v{}.sst", version));
+            let sst = Arc::new(SsTable::open(FileObject::open(&path).unwrap(), None, version).unwrap());
+            assert_eq!(sst.version, version as u32);
+            assert!(sst.num_of_blocks() > 1);
+            assert_eq!(sst.first_key().key_ref(), b"key_000");
+            assert_eq!(sst.last_key().key_ref(), b"key_099");
+            assert_eq!(sst.max_ts(), TS_DEFAULT);
+
+            let key = KeySlice::from_slice(b"key_042", TS_RANGE_BEGIN);
+            assert_eq!(sst.get(key).unwrap().as_deref(), Some(&b"value_042"[..]));
+            assert_eq!(sst.get(KeySlice::from_slice(b"key_100", TS_RANGE_BEGIN)).unwrap(), None);
+
+            let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+            for i in 0..100 {
+                assert!(iter.is_valid());
+                assert_eq!(iter.key(), KeySlice::from_slice(format!("key_{:03}", i).as_bytes(), TS_DEFAULT));
+                assert_eq!(iter.value(), format!("value_{:03}", i).as_bytes());
+                iter.next().unwrap();
+            }
+            assert!(!iter.is_valid());
+        }
+    }
+
+    #[test]
+    fn flipped_bit_in_a_block_is_reported_with_its_position() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("7.sst");
+        let mut builder = SsTableBuilder::create(&path, 128, 10, CompressionType::None).unwrap();
+        for i in 0..100 {
+            let key = format!("key_{:03}", i);
+            builder.add(KeySlice::from_slice(key.as_bytes(), 1), b"value").unwrap();
+        }
+        let sst = builder.build(7, None).unwrap();
+        let block_idx = 3;
+        assert!(sst.num_of_blocks() > block_idx + 1);
+        let offset = sst.block_meta[block_idx].offset + 8;
+        let corrupted_key = sst.block_meta[block_idx].first_key.key_ref().to_vec();
+        let intact_key = sst.block_meta[block_idx + 1].first_key.key_ref().to_vec();
+        drop(sst);
+
+        let mut data = std::fs::read(&path).unwrap();
+        data[offset] ^= 0x10;
+        std::fs::write(&path, &data).unwrap();
+
+        let sst = SsTable::open(FileObject::open(&path).unwrap(), None, 7).unwrap();
+        let e = sst.get(KeySlice::from_slice(&corrupted_key, TS_RANGE_BEGIN)).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<SsTableCorruption>(),
+            Some(&SsTableCorruption::Block { sst_id: 7, block_idx })
+        );
+        // the other blocks are still readable, read at the version itself so the search starts
+        // in the next block rather than at the end of the corrupted one
+        assert!(sst.get(KeySlice::from_slice(&intact_key, 1)).unwrap().is_some());
+    }
+
+    #[test]
+    fn block_without_content_is_reported_as_corrupted() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("7.sst");
+        let mut builder = SsTableBuilder::create(&path, 128, 10, CompressionType::None).unwrap();
+        for i in 0..100 {
+            let key = format!("key_{:03}", i);
+            builder.add(KeySlice::from_slice(key.as_bytes(), 1), b"value").unwrap();
+        }
+        builder.build(7, None).unwrap();
+
+        // the checksum of nothing is 0, so 4 zero bytes pass as a block with an empty content
+        let mut data = std::fs::read(&path).unwrap();
+        data[..4].fill(0);
+        std::fs::write(&path, &data).unwrap();
+        let mut sst = SsTable::open(FileObject::open(&path).unwrap(), None, 7).unwrap();
+        sst.block_meta[1].offset = 4;
+        let e = sst.get(KeySlice::from_slice(b"key_000", TS_RANGE_BEGIN)).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<SsTableCorruption>(),
+            Some(&SsTableCorruption::Block { sst_id: 7, block_idx: 0 })
+        );
+    }
+
+    #[test]
+    fn properties_are_recorded_and_read_back() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("3.sst");
+        let mut builder = SsTableBuilder::create(&path, 128, 10, CompressionType::None).unwrap();
+        let mut expected = TableProperties::default();
+        for i in 0..100u64 {
+            let key = format!("key_{}", i);
+            // every fifth key is deleted, and the newest version of each key comes first
+            for ts in [i + 100, i] {
+                let value: &[u8] = if i % 5 == 0 && ts > i { b"" } else { b"value" };
+                builder.add(KeySlice::from_slice(key.as_bytes(), ts), value).unwrap();
+                expected.num_entries += 1;
+                expected.num_tombstones += value.is_empty() as u64;
+                expected.raw_key_size += key.len() as u64;
+                expected.raw_value_size += value.len() as u64;
+            }
+        }
+        expected.max_ts = 199;
+        let sst = builder.build(3, None).unwrap();
+        let check = |properties: &TableProperties| {
+            assert!(properties.creation_time > 0);
+            let properties = TableProperties {
+                creation_time: 0,
+                ..properties.clone()
+            };
+            assert_eq!(properties, expected);
+        };
+        check(sst.properties().unwrap());
+        assert_eq!(sst.max_ts(), 199);
+        drop(sst);
+
+        let sst = SsTable::open(FileObject::open(&path).unwrap(), None, 3).unwrap();
+        check(sst.properties().unwrap());
+        assert_eq!(sst.max_ts(), 199);
+    }
+
+    #[test]
+    fn truncated_file_or_other_file_has_an_invalid_footer() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("5.sst");
+        let mut builder = SsTableBuilder::create(&path, 128, 10, CompressionType::None).unwrap();
+        for i in 0..100 {
+            builder.add(KeySlice::from_slice(format!("key_{:03}", i).as_bytes(), 1), b"value").unwrap();
+        }
+        drop(builder.build(5, None).unwrap());
+        let data = std::fs::read(&path).unwrap();
+        let open = |content: &[u8]| {
+            std::fs::write(&path, content).unwrap();
+            SsTable::open(FileObject::open(&path).unwrap(), None, 5).map(|_| ())
+        };
+        let invalid_footer = Some(&SsTableCorruption::Footer { sst_id: 5 });
+        for len in [data.len() - 1, data.len() - 8, data.len() / 2, 4, 0] {
+            let e = open(&data[..len]).unwrap_err();
+            assert_eq!(e.downcast_ref::<SsTableCorruption>(), invalid_footer, "truncated to {}", len);
+        }
+        let e = open(b"this is a text file and not an SsTable at all").unwrap_err();
+        assert_eq!(e.downcast_ref::<SsTableCorruption>(), invalid_footer);
+    }
+
+
+    #[test]
+    fn estimated_size_follows_the_blocks_written_so_far() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("9.sst");
+        let mut builder = SsTableBuilder::create(&path, 128, 10, CompressionType::None).unwrap();
+        assert_eq!(builder.estimated_size(), 0);
+        let mut last_size = 0;
+        for i in 0..100 {
+            let num_blocks = builder.block_meta.len();
+            builder.add(KeySlice::from_slice(format!("key_{:03}", i).as_bytes(), 1), b"value").unwrap();
+            let size = builder.estimated_size();
+            // it only grows when a block is finished, and that block is already in the file
+            assert_eq!(size > last_size, builder.block_meta.len() > num_blocks);
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), size as u64);
+            last_size = size;
+        }
+        assert!(builder.block_meta.len() > 5);
+        let sst = builder.build(9, None).unwrap();
+        assert!(sst.table_size() > last_size as u64);
+    }
+
 }
@@ -0,0 +1,191 @@
+use anyhow::{bail, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// A bloom filter over the keys of one SsTable. It answers "definitely not here" or "maybe here",
+/// so a point lookup can skip SsTables without reading any of their blocks.
+pub struct Bloom {
+    /// the bit array of the filter
+    pub(crate) filter: Bytes,
+    /// the number of hash functions
+    pub(crate) k: u8,
+}
+
+trait BitSlice {
+    fn get_bit(&self, idx: usize) -> bool;
+    fn bit_len(&self) -> usize;
+}
+
+trait BitSliceMut {
+    fn set_bit(&mut self, idx: usize, val: bool);
+}
+
+impl<T: AsRef<[u8]>> BitSlice for T {
+    fn get_bit(&self, idx: usize) -> bool {
+        let pos = idx / 8;
+        let offset = idx % 8;
+        (self.as_ref()[pos] & (1 << offset)) != 0
+    }
+
+    fn bit_len(&self) -> usize {
+        self.as_ref().len() * 8
+    }
+}
+
+impl<T: AsMut<[u8]>> BitSliceMut for T {
+    fn set_bit(&mut self, idx: usize, val: bool) {
+        let pos = idx / 8;
+        let offset = idx % 8;
+        if val {
+            self.as_mut()[pos] |= 1 << offset;
+        } else {
+            self.as_mut()[pos] &= !(1 << offset);
+        }
+    }
+}
+
+impl Bloom {
+    /// The encoded filter looks like
+    /// | filter bits | k (u8) |
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.is_empty() {
+            bail!("bloom filter is empty");
+        }
+        let filter = &buf[..buf.len() - 1];
+        let k = buf[buf.len() - 1];
+        Ok(Self {
+            filter: Bytes::copy_from_slice(filter),
+            k,
+        })
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend(&self.filter);
+        buf.put_u8(self.k);
+    }
+
+    /// The hash that the filter is built from, it has to stay the same across versions since the
+    /// filters are stored on the disk.
+    pub fn hash(key: &[u8]) -> u32 {
+        farmhash::fingerprint32(key)
+    }
+
+    /// Build the filter from the hashes of all keys. Every key sets `k` bits, where the positions
+    /// come from double hashing on the single 32-bit hash.
+    pub fn build_from_key_hashes(keys: &[u32], bits_per_key: usize) -> Self {
+        // ln 2 * bits_per_key hash functions give the lowest false positive rate
+        let k = (bits_per_key as f64 * 0.69) as u32;
+        let k = k.clamp(1, 30);
+        let nbits = (keys.len() * bits_per_key).max(64);
+        let nbytes = nbits.div_ceil(8);
+        let nbits = nbytes * 8;
+        let mut filter = BytesMut::with_capacity(nbytes);
+        filter.resize(nbytes, 0);
+        for h in keys {
+            let mut h = *h;
+            let delta = h.rotate_left(15);
+            for _ in 0..k {
+                let bit_pos = (h as usize) % nbits;
+                filter.set_bit(bit_pos, true);
+                h = h.wrapping_add(delta);
+            }
+        }
+        Self {
+            filter: filter.freeze(),
+            k: k as u8,
+        }
+    }
+
+    pub fn may_contain(&self, mut h: u32) -> bool {
+        if self.k > 30 {
+            // reserved for new encodings, treat it as a match to stay correct
+            return true;
+        }
+        let nbits = self.filter.bit_len();
+        let delta = h.rotate_left(15);
+        for _ in 0..self.k {
+            let bit_pos = h % (nbits as u32);
+            if !self.filter.get_bit(bit_pos as usize) {
+                return false;
+            }
+            h = h.wrapping_add(delta);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tempfile::tempdir;
+    use super::Bloom;
+    use crate::key::{KeySlice, TS_RANGE_BEGIN};
+    use crate::lsm_storage::BlockCache;
+    use crate::table::compress::CompressionType;
+    use crate::table::{FileObject, Footer, SsTable, SsTableBuilder, SIZEOF_CHECKSUM};
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key_{:05}", i * 2).into_bytes()
+    }
+
+    fn build(path: &std::path::Path, bloom_bits_per_key: usize, block_cache: Option<Arc<BlockCache>>) -> SsTable {
+        let mut builder = SsTableBuilder::create(path, 256, bloom_bits_per_key, CompressionType::None).unwrap();
+        for i in 0..1000 {
+            builder.add(KeySlice::from_slice(&key(i), 1), b"value").unwrap();
+        }
+        builder.build(0, block_cache).unwrap()
+    }
+
+    #[test]
+    fn every_added_key_may_be_contained() {
+        let hashes = (0..1000).map(|i| Bloom::hash(&key(i))).collect::<Vec<_>>();
+        let bloom = Bloom::build_from_key_hashes(&hashes, 10);
+        assert!(hashes.iter().all(|&h| bloom.may_contain(h)));
+        // and survives its encoding
+        let mut buf = Vec::new();
+        bloom.encode(&mut buf);
+        let bloom = Bloom::decode(&buf).unwrap();
+        assert!(hashes.iter().all(|&h| bloom.may_contain(h)));
+        // with 10 bits per key about 1% of the other keys get through
+        let false_positives = (0..1000)
+            .filter(|i| bloom.may_contain(Bloom::hash(format!("other_{}", i).as_bytes())))
+            .count();
+        assert!(false_positives < 50, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn zero_bits_per_key_writes_no_filter() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.sst");
+        let sst = build(&path, 0, None);
+        assert!(sst.bloom.is_none());
+        drop(sst);
+        // the section only has its checksum
+        let footer = Footer::read(&FileObject::open(&path).unwrap(), 0).unwrap();
+        assert_eq!(footer.bloom.1 - footer.bloom.0, SIZEOF_CHECKSUM as u64);
+        let sst = SsTable::open(FileObject::open(&path).unwrap(), None, 0).unwrap();
+        assert!(sst.bloom.is_none());
+        let value = sst.get(KeySlice::from_slice(&key(42), TS_RANGE_BEGIN)).unwrap();
+        assert_eq!(value.as_deref(), Some(&b"value"[..]));
+    }
+
+    #[test]
+    fn key_outside_the_filter_reads_no_block() {
+        let dir = tempdir().unwrap();
+        let block_cache = Arc::new(BlockCache::new(1024));
+        let sst = build(&dir.path().join("0.sst"), 10, Some(block_cache.clone()));
+        let cached_blocks = || (0..sst.num_of_blocks()).filter(|&idx| block_cache.contains_key(&(0, idx))).count();
+
+        // within the key range of the SsTable, but not one of its keys
+        let missing = (0..1000)
+            .map(|i| format!("key_{:05}", i * 2 + 1).into_bytes())
+            .find(|key| !sst.bloom.as_ref().unwrap().may_contain(Bloom::hash(key)))
+            .unwrap();
+        assert!(sst.may_contain(&missing));
+        assert_eq!(sst.get(KeySlice::from_slice(&missing, TS_RANGE_BEGIN)).unwrap(), None);
+        assert_eq!(cached_blocks(), 0);
+
+        let value = sst.get(KeySlice::from_slice(&key(42), TS_RANGE_BEGIN)).unwrap();
+        assert_eq!(value.as_deref(), Some(&b"value"[..]));
+        assert_eq!(cached_blocks(), 1);
+    }
+}
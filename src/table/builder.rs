@@ -1,81 +1,181 @@
-use std::path::Path;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use crate::block::BlockBuilder;
-use crate::lsm_storage::BlockCache;
-use crate::table::{BlockMeta, FileObject, SsTable};
+use crate::key::{KeySlice, KeyVec};
+use crate::lsm_storage::{sync_file, BlockCache};
+use crate::table::{BlockMeta, FileObject, SsTable, TableProperties, SIZEOF_CHECKSUM, SST_FORMAT_VERSION, SST_MAGIC};
+use crate::table::bloom::Bloom;
+use crate::table::compress::CompressionType;
 use anyhow::Result;
 use bytes::BufMut;
 
 pub struct SsTableBuilder {
     builder: BlockBuilder,
-    first_key: Vec<u8>,
-    last_key: Vec<u8>,
-    data: Vec<u8>,
+    first_key: KeyVec,
+    last_key: KeyVec,
+    // the output file, every finished block is appended to it right away so only the block being
+    // built is kept in memory
+    file: File,
+    path: PathBuf,
+    // the number of bytes written to the file so far, i.e. the offset of the next block
+    offset: usize,
     pub(crate) block_meta: Vec<BlockMeta>,
     target_block_size: usize,
+    // the hashes of every key added, the bloom filter is built from them at the end
+    key_hashes: Vec<u32>,
+    // 0 means the SsTable has no bloom filter
+    bloom_bits_per_key: usize,
+    compression: CompressionType,
+    // creation_time is only filled in by build
+    properties: TableProperties,
 }
 
 impl SsTableBuilder {
-    fn new(target_block_size: usize) -> Self {
-        Self {
+    /// Create the SsTable file at `path` (truncating whatever is there) and a builder that writes
+    /// into it.
+    pub fn create(
+        path: impl AsRef<Path>,
+        target_block_size: usize,
+        bloom_bits_per_key: usize,
+        compression: CompressionType,
+    ) -> Result<Self> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path.as_ref())?;
+        Ok(Self {
             builder: BlockBuilder::new(target_block_size),
-            first_key: Vec::new(),
-            last_key: Vec::new(),
-            data: Vec::new(),
+            first_key: KeyVec::new(),
+            last_key: KeyVec::new(),
+            file,
+            path: path.as_ref().to_path_buf(),
+            offset: 0,
             block_meta: Vec::new(),
-            target_block_size
-        }
+            target_block_size,
+            key_hashes: Vec::new(),
+            bloom_bits_per_key,
+            compression,
+            properties: TableProperties::default(),
+        })
     }
 
-    fn add(&mut self, key: &[u8], value: &[u8]) {
+    /// Add a key-value pair, keys must come in the order of `KeySlice`, so the versions of a user
+    /// key come one after another from the newest to the oldest.
+    pub fn add(&mut self, key: KeySlice, value: &[u8]) -> Result<()> {
+        if self.bloom_bits_per_key > 0 {
+            let hash = Bloom::hash(key.key_ref());
+            // the versions of a key come one after another and all have the same hash
+            if self.key_hashes.last() != Some(&hash) {
+                self.key_hashes.push(hash);
+            }
+        }
+        self.properties.num_entries += 1;
+        if value.is_empty() {
+            self.properties.num_tombstones += 1;
+        }
+        self.properties.raw_key_size += key.key_len() as u64;
+        self.properties.raw_value_size += value.len() as u64;
+        self.properties.max_ts = self.properties.max_ts.max(key.ts());
+
         if self.first_key.is_empty() {
-            self.first_key.clear();
-            // everything that implements IntoIterator<Item = u8> can be used in .extend()
-            self.first_key.extend(key);
+            self.first_key.set_from_slice(key);
         }
 
         if self.builder.add(key, value) {
-            self.last_key.clear();
-            self.last_key.extend(key);
-            return
+            self.last_key.set_from_slice(key);
+            return Ok(());
         }
 
-        self.finish_block();
+        self.finish_block()?;
         assert!(self.builder.add(key, value));
-        self.first_key.clear();
-        self.first_key.extend(key);
-        self.last_key.clear();
-        self.last_key.extend(key);
+        self.first_key.set_from_slice(key);
+        self.last_key.set_from_slice(key);
+        Ok(())
+    }
+
+    /// The size of the SsTable if it is built now, only the data blocks already written to the
+    /// file are counted since they are the bulk of it.
+    pub fn estimated_size(&self) -> usize {
+        self.offset
     }
 
-    fn finish_block(&mut self) {
+    pub fn is_empty(&self) -> bool {
+        self.block_meta.is_empty() && self.builder.is_empty()
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
         let old_block_builder = std::mem::replace(&mut self.builder, BlockBuilder::new(self.target_block_size));
         let encoded_block = old_block_builder.build().encode();
+        let compressed_block = self.compression.compressor().compress(&encoded_block);
+        // there is no point in paying for decompression when it does not save any space
+        let (compression, block) = if compressed_block.len() < encoded_block.len() {
+            (self.compression, &compressed_block[..])
+        } else {
+            (CompressionType::None, &encoded_block[..])
+        };
         self.block_meta.push(
             BlockMeta {
-                offset: self.data.len(),
-                first_key: std::mem::take(&mut self.first_key).into(),
-                last_key: std::mem::take(&mut self.last_key).into(),
+                offset: self.offset,
+                first_key: std::mem::replace(&mut self.first_key, KeyVec::new()).into_key_bytes(),
+                last_key: std::mem::replace(&mut self.last_key, KeyVec::new()).into_key_bytes(),
             }
         );
-        self.data.extend(encoded_block);
+        let mut buf = Vec::with_capacity(1 + block.len() + SIZEOF_CHECKSUM);
+        buf.put_u8(compression as u8);
+        buf.extend(block);
+        let checksum = crc32fast::hash(&buf);
+        buf.put_u32(checksum);
+        self.file.write_all(&buf)?;
+        self.offset += buf.len();
+        Ok(())
     }
 
-    /// Builds the SSTable and writes it to the given path. Use the `FileObject` structure to manipulate the disk objects.
+    /// Writes the last block and everything after the data blocks, then syncs the file.
     pub fn build(
         mut self,
         id: usize,
         block_cache: Option<Arc<BlockCache>>,
-        path: impl AsRef<Path>,
     ) -> Result<SsTable> {
-        self.finish_block();
-        let mut buf = self.data;
-        let meta_offset = buf.len();
+        self.finish_block()?;
+        // the offsets in the footer are offsets in the file, while buf only holds what comes after
+        // the data blocks
+        let meta_offset = self.offset;
+        let mut buf = Vec::new();
         // encode the block meta, it will format the block_meta and put it after the block data section
         BlockMeta::encode_block_meta(&self.block_meta, &mut buf);
-        // the length of the offset section(the length of the block data section), should occupy the last four bytes
-        buf.put_u32(meta_offset as u32);
-        let file = FileObject::create(path.as_ref(), buf)?;
+        buf.put_u32(crc32fast::hash(&buf));
+        // the bloom filter goes after the block meta, an empty section means there is no filter
+        let bloom = if self.bloom_bits_per_key > 0 {
+            Some(Bloom::build_from_key_hashes(&self.key_hashes, self.bloom_bits_per_key))
+        } else {
+            None
+        };
+        let bloom_start = buf.len();
+        if let Some(bloom) = &bloom {
+            bloom.encode(&mut buf);
+        }
+        buf.put_u32(crc32fast::hash(&buf[bloom_start..]));
+        self.properties.creation_time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let properties_start = buf.len();
+        self.properties.encode(&mut buf);
+        buf.put_u32(crc32fast::hash(&buf[properties_start..]));
+        let bloom_offset = meta_offset + bloom_start;
+        let properties_offset = meta_offset + properties_start;
+        // the footer points at every section above, and ends with the version and the magic so a
+        // reader can tell what (and whether) it is reading before trusting any offset
+        buf.put_u64(meta_offset as u64);
+        buf.put_u64(bloom_offset as u64);
+        buf.put_u64(properties_offset as u64);
+        buf.put_u32(SST_FORMAT_VERSION);
+        buf.put_u64(SST_MAGIC);
+        self.file.write_all(&buf)?;
+        sync_file(&self.file, &self.path)?;
+        let file = FileObject(Some(self.file), (meta_offset + buf.len()) as u64);
         Ok(SsTable {
             id,
             file,
@@ -84,6 +184,9 @@ impl SsTableBuilder {
             block_meta: self.block_meta,
             block_meta_offset: meta_offset,
             block_cache,
+            bloom,
+            properties: Some(self.properties),
+            version: SST_FORMAT_VERSION,
         })
     }
 }
\ No newline at end of file
@@ -0,0 +1,129 @@
+use anyhow::{anyhow, bail, Result};
+
+/// Turns an encoded block into the bytes that are written to the disk and back.
+pub trait BlockCompressor: Send + Sync {
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>>;
+}
+
+pub struct NoCompressor;
+
+impl BlockCompressor for NoCompressor {
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        data.to_vec()
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+}
+
+/// LZ4 is fast enough to run on every block read that misses the block cache.
+pub struct Lz4Compressor;
+
+impl BlockCompressor for Lz4Compressor {
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        lz4_flex::block::compress_prepend_size(data)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        lz4_flex::block::decompress_size_prepended(data).map_err(|e| anyhow!("failed to decompress block: {}", e))
+    }
+}
+
+/// The compression of a block, stored as a single byte in front of it so that blocks written
+/// with different settings can live in the same SsTable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CompressionType {
+    None = 0,
+    Lz4 = 1,
+}
+
+impl CompressionType {
+    pub fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(CompressionType::None),
+            1 => Ok(CompressionType::Lz4),
+            _ => bail!("unknown compression type {}", value),
+        }
+    }
+
+    pub fn compressor(self) -> &'static dyn BlockCompressor {
+        match self {
+            CompressionType::None => &NoCompressor,
+            CompressionType::Lz4 => &Lz4Compressor,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tempfile::tempdir;
+    use super::CompressionType;
+    use crate::iterator::StorageIterator;
+    use crate::key::KeySlice;
+    use crate::table::{SsTable, SsTableBuilder, SsTableIterator};
+
+    fn build(values: &[Vec<u8>]) -> (tempfile::TempDir, Arc<SsTable>) {
+        let dir = tempdir().unwrap();
+        let mut builder = SsTableBuilder::create(dir.path().join("0.sst"), 4096, 10, CompressionType::Lz4).unwrap();
+        for (i, value) in values.iter().enumerate() {
+            builder.add(KeySlice::from_slice(format!("key_{:03}", i).as_bytes(), 1), value).unwrap();
+        }
+        let sst = Arc::new(builder.build(0, None).unwrap());
+        (dir, sst)
+    }
+
+    // the type byte in front of every block as it is on the disk
+    fn block_types(sst: &SsTable) -> Vec<u8> {
+        sst.block_meta
+            .iter()
+            .map(|meta| sst.file.read(meta.offset as u64, 1).unwrap()[0])
+            .collect()
+    }
+
+    fn assert_round_trip(sst: Arc<SsTable>, values: &[Vec<u8>]) {
+        let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+        for (i, value) in values.iter().enumerate() {
+            assert!(iter.is_valid());
+            assert_eq!(iter.key().key_ref(), format!("key_{:03}", i).as_bytes());
+            assert_eq!(iter.value(), &value[..]);
+            iter.next().unwrap();
+        }
+        assert!(!iter.is_valid());
+    }
+
+    #[test]
+    fn compressible_blocks_are_stored_with_lz4() {
+        let values = (0..100).map(|i| format!("value_{}", i % 3).repeat(20).into_bytes()).collect::<Vec<_>>();
+        let (_dir, sst) = build(&values);
+        assert!(sst.num_of_blocks() > 1);
+        assert!(block_types(&sst).iter().all(|&ty| ty == CompressionType::Lz4 as u8));
+        // smaller than the values alone
+        assert!(sst.table_size() < values.iter().map(|v| v.len() as u64).sum::<u64>());
+        assert_round_trip(sst, &values);
+    }
+
+    #[test]
+    fn incompressible_blocks_fall_back_to_none() {
+        // xorshift, which lz4 finds no repetition in, and every value fills a block of its own
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let values = (0..10)
+            .map(|_| {
+                (0..5000)
+                    .map(|_| {
+                        state ^= state << 13;
+                        state ^= state >> 7;
+                        state ^= state << 17;
+                        state as u8
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let (_dir, sst) = build(&values);
+        assert_eq!(block_types(&sst), vec![CompressionType::None as u8; 10]);
+        assert_round_trip(sst, &values);
+    }
+}
@@ -3,8 +3,9 @@ use std::sync::Arc;
 use anyhow::Result;
 
 use super::SsTable;
-use crate::block::{Block, BlockIterator};
+use crate::block::BlockIterator;
 use crate::iterator::StorageIterator;
+use crate::key::KeySlice;
 
 /// An iterator over the contents of an SSTable.
 pub struct SsTableIterator {
@@ -20,7 +21,7 @@ impl SsTableIterator {
         Ok((0, block_iterator))
     }
 
-    pub fn create_block_iterator_and_seek_to_key(table: &Arc<SsTable>, key: &[u8]) -> Result<(usize, BlockIterator)> {
+    pub fn create_block_iterator_and_seek_to_key(table: &Arc<SsTable>, key: KeySlice) -> Result<(usize, BlockIterator)> {
         // find which block is the key located, returns the index
         let mut block_index = table.find_block_idx(key);
         let block = table.read_block_cache(block_index)?;
@@ -41,7 +42,7 @@ impl SsTableIterator {
         Ok((block_index, block_iterator))
     }
 
-    pub fn create_and_seek_to_first(&self, table: Arc<SsTable>) -> Result<Self> {
+    pub fn create_and_seek_to_first(table: Arc<SsTable>) -> Result<Self> {
         let(block_idx, block_iterator) =
             Self::create_first_block_iterator_and_seek_to_first_pair(&table)?;
         Ok(Self {
@@ -59,7 +60,7 @@ impl SsTableIterator {
         Ok(())
     }
 
-    pub fn create_and_seek_to_key(table: Arc<SsTable>, key: &[u8]) -> Result<Self> {
+    pub fn create_and_seek_to_key(table: Arc<SsTable>, key: KeySlice) -> Result<Self> {
         let(block_idx, block_iterator) = Self::create_block_iterator_and_seek_to_key(&table, key)?;
         Ok(Self {
             block_idx,
@@ -68,7 +69,7 @@ impl SsTableIterator {
         })
     }
 
-    pub fn seek_to_key(&mut self, key: &[u8]) -> Result<()> {
+    pub fn seek_to_key(&mut self, key: KeySlice) -> Result<()> {
         let (blk_idx, blk_iter) = Self::create_block_iterator_and_seek_to_key(&self.table, key)?;
         self.block_iter = blk_iter;
         self.block_idx = blk_idx;
@@ -77,6 +78,8 @@ impl SsTableIterator {
 }
 
 impl StorageIterator for SsTableIterator {
+    type KeyType<'a> = KeySlice<'a>;
+
     // Note: You may want to check if the current block iterator is valid after the move.
     fn next(&mut self) -> Result<()> {
         self.block_iter.next();
@@ -94,7 +97,7 @@ impl StorageIterator for SsTableIterator {
     }
 
     // Return the `key` that's held by the underlying block iterator.
-    fn key(&self) -> &[u8] {
+    fn key(&self) -> KeySlice<'_> {
         self.block_iter.key()
     }
 
@@ -0,0 +1,56 @@
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut};
+
+/// Statistics about the content of one SsTable, collected by `SsTableBuilder` while it is built
+/// and stored in the properties section of the file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableProperties {
+    /// the number of key-value pairs, tombstones included
+    pub num_entries: u64,
+    /// the number of deletions (entries with an empty value)
+    pub num_tombstones: u64,
+    /// the total length of all keys before prefix compression and block compression
+    pub raw_key_size: u64,
+    /// the total length of all values before block compression
+    pub raw_value_size: u64,
+    /// when the SsTable was built, in seconds since the unix epoch
+    pub creation_time: u64,
+    /// the largest commit timestamp of all keys, 0 for the files written before keys had one
+    pub max_ts: u64,
+}
+
+impl TableProperties {
+    fn encoded_size(version: u32) -> usize {
+        // max_ts was added in version 4
+        if version >= 4 {
+            size_of::<u64>() * 6
+        } else {
+            size_of::<u64>() * 5
+        }
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put_u64(self.num_entries);
+        buf.put_u64(self.num_tombstones);
+        buf.put_u64(self.raw_key_size);
+        buf.put_u64(self.raw_value_size);
+        buf.put_u64(self.creation_time);
+        buf.put_u64(self.max_ts);
+    }
+
+    /// Decode the properties of a SsTable written with format `version`.
+    pub fn decode(mut buf: &[u8], version: u32) -> Result<Self> {
+        let encoded_size = Self::encoded_size(version);
+        if buf.len() != encoded_size {
+            bail!("table properties should be {} bytes, got {}", encoded_size, buf.len());
+        }
+        Ok(Self {
+            num_entries: buf.get_u64(),
+            num_tombstones: buf.get_u64(),
+            raw_key_size: buf.get_u64(),
+            raw_value_size: buf.get_u64(),
+            creation_time: buf.get_u64(),
+            max_ts: if version >= 4 { buf.get_u64() } else { 0 },
+        })
+    }
+}
@@ -1,3 +1,322 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, BufMut, Bytes};
+use crossbeam_skiplist::SkipMap;
+use crate::key::{KeyBytes, KeySlice, TS_DEFAULT};
+use crate::lsm_storage::{sync_file, sync_parent_dir};
+use parking_lot::Mutex;
+
+/// The first 8 bytes of every WAL since version 2, followed by the format version (u32). A WAL
+/// without them was written before the header existed.
+pub const WAL_MAGIC: u64 = 0x4d69_6e69_4c57_414c;
+
+/// The version of the WAL format written by `Wal`.
+/// 1: no header, `| key_len (u32) | key | value_len (u32) | value | checksum (u32) |` records.
+/// 2: the header, and every key in the records is followed by its timestamp (u64).
+pub const WAL_FORMAT_VERSION: u32 = 2;
+
+const WAL_HEADER_SIZE: usize = size_of::<u64>() + size_of::<u32>();
+
+/// The write-ahead log of one memtable. Every write goes here before it goes into the skipmap,
+/// so a memtable that has not been flushed yet can be rebuilt after a crash.
 pub(crate) struct Wal {
+    // BufWriter so that a put does not turn into a syscall, the data only reaches the disk
+    // for sure after `sync`
+    file: Mutex<BufWriter<File>>,
+    path: PathBuf,
+    // the format of the records in the file, only a WAL of the current version takes new ones
+    version: u32,
+}
+
+impl Wal {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .create_new(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("failed to create WAL {:?}", path))?;
+        write_header(&mut file)?;
+        // a sync of the WAL only covers its content, the WAL itself has to survive a crash
+        // before anything relies on it
+        sync_file(&file, path)?;
+        sync_parent_dir(path)?;
+        Ok(Self {
+            file: Mutex::new(BufWriter::new(file)),
+            path: path.to_path_buf(),
+            version: WAL_FORMAT_VERSION,
+        })
+    }
+
+    /// Replay every record of the WAL into `map` and keep the WAL open for new records. Returns
+    /// the WAL and the number of bytes that were replayed, which is what the memtable uses as its
+    /// approximate size.
+    pub fn recover(path: impl AsRef<Path>, map: &SkipMap<KeyBytes, Bytes>) -> Result<(Self, usize)> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to recover from WAL {:?}", path))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        let (version, mut offset) = if buf.len() >= WAL_HEADER_SIZE && (&buf[..]).get_u64() == WAL_MAGIC {
+            let version = (&buf[size_of::<u64>()..]).get_u32();
+            if version != WAL_FORMAT_VERSION {
+                bail!("unsupported WAL format version {} in WAL {:?}", version, path);
+            }
+            (version, WAL_HEADER_SIZE)
+        } else if buf.len() < WAL_HEADER_SIZE {
+            // too short for a record of any version, so this is a WAL whose header was torn
+            // while it was being created, it gets a fresh header and nothing is lost
+            file.set_len(0)?;
+            write_header(&mut file)?;
+            sync_file(&file, path)?;
+            buf.clear();
+            (WAL_FORMAT_VERSION, 0)
+        } else {
+            (1, 0)
+        };
+        let mut replayed_size = 0;
+        loop {
+            match read_record(&buf[offset..], version) {
+                WalRecord::Complete { key, ts, value, len } => {
+                    replayed_size += key.len() + value.len();
+                    map.insert(KeyBytes::from_bytes_with_ts(key, ts), value);
+                    offset += len;
+                }
+                WalRecord::Torn => break,
+                WalRecord::Corrupted { len } => {
+                    // after a power loss the tail that was never synced can be anything, even
+                    // records of the right length full of garbage or zeros, only a bad record with
+                    // good records after it means that synced data is damaged
+                    if has_complete_record(&buf[offset + len..], version) {
+                        bail!("WAL {:?} is corrupted, checksum mismatch", path);
+                    }
+                    break;
+                }
+            }
+        }
+        // cut the torn records off, otherwise new records would be appended after the garbage
+        if offset != buf.len() {
+            file.set_len(offset as u64)?;
+        }
+        Ok((
+            Self {
+                file: Mutex::new(BufWriter::new(file)),
+                path: path.to_path_buf(),
+                version,
+            },
+            replayed_size,
+        ))
+    }
+
+    /// Append one record for each pair, the records of a batch are written in one go.
+    pub fn put_batch(&self, batch: &[(KeySlice, &[u8])]) -> Result<()> {
+        // an old WAL is only ever replayed, its memtable is frozen right away
+        if self.version != WAL_FORMAT_VERSION {
+            bail!("cannot append to WAL {:?} of format version {}", self.path, self.version);
+        }
+        let mut file = self.file.lock();
+        let mut buf: Vec<u8> = Vec::new();
+        for (key, value) in batch {
+            let start = buf.len();
+            buf.put_u32(key.key_len() as u32);
+            buf.put_slice(key.key_ref());
+            buf.put_u64(key.ts());
+            buf.put_u32(value.len() as u32);
+            buf.put_slice(value);
+            let checksum = crc32fast::hash(&buf[start..]);
+            buf.put_u32(checksum);
+        }
+        file.write_all(&buf)?;
+        Ok(())
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        let mut file = self.file.lock();
+        file.flush()?;
+        sync_file(file.get_ref(), &self.path)?;
+        Ok(())
+    }
+}
+
+fn write_header(file: &mut File) -> Result<()> {
+    let mut header = Vec::with_capacity(WAL_HEADER_SIZE);
+    header.put_u64(WAL_MAGIC);
+    header.put_u32(WAL_FORMAT_VERSION);
+    file.write_all(&header)?;
+    Ok(())
+}
+
+enum WalRecord {
+    Complete { key: Bytes, ts: u64, value: Bytes, len: usize },
+    // the buffer ends in the middle of the record
+    Torn,
+    // the record is complete but its checksum does not match
+    Corrupted { len: usize },
+}
+
+// Each record looks like
+// | key_len (u32) | key | ts (u64) | value_len (u32) | value | checksum (u32) |
+// where the ts is missing in version 1, and its keys are read as `TS_DEFAULT`.
+fn read_record(buf: &[u8], version: u32) -> WalRecord {
+    let ts_len = if version >= 2 { size_of::<u64>() } else { 0 };
+    let mut rbuf = buf;
+    if rbuf.remaining() < size_of::<u32>() {
+        return WalRecord::Torn;
+    }
+    let key_len = rbuf.get_u32() as usize;
+    if rbuf.remaining() < key_len + ts_len + size_of::<u32>() {
+        return WalRecord::Torn;
+    }
+    let key = Bytes::copy_from_slice(&rbuf[..key_len]);
+    rbuf.advance(key_len);
+    let ts = if version >= 2 { rbuf.get_u64() } else { TS_DEFAULT };
+    let value_len = rbuf.get_u32() as usize;
+    if rbuf.remaining() < value_len + size_of::<u32>() {
+        return WalRecord::Torn;
+    }
+    let value = Bytes::copy_from_slice(&rbuf[..value_len]);
+    rbuf.advance(value_len);
+    let record_len = size_of::<u32>() * 2 + key_len + ts_len + value_len;
+    let checksum = rbuf.get_u32();
+    let len = record_len + size_of::<u32>();
+    if checksum != crc32fast::hash(&buf[..record_len]) {
+        return WalRecord::Corrupted { len };
+    }
+    WalRecord::Complete { key, ts, value, len }
+}
+
+fn has_complete_record(mut buf: &[u8], version: u32) -> bool {
+    loop {
+        match read_record(buf, version) {
+            WalRecord::Complete { .. } => return true,
+            WalRecord::Torn => return false,
+            WalRecord::Corrupted { len } => buf = &buf[len..],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use bytes::Bytes;
+    use crossbeam_skiplist::SkipMap;
+    use tempfile::tempdir;
+    use bytes::BufMut;
+    use super::{Wal, WAL_FORMAT_VERSION, WAL_MAGIC};
+    use crate::key::{KeyBytes, KeySlice, TS_DEFAULT};
+
+    fn put(wal: &Wal, key: &[u8], ts: u64, value: &[u8]) {
+        wal.put_batch(&[(KeySlice::from_slice(key, ts), value)]).unwrap();
+        wal.sync().unwrap();
+    }
+
+    fn append(path: &std::path::Path, data: &[u8]) {
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(data).unwrap();
+    }
+
+    fn keys(map: &SkipMap<KeyBytes, Bytes>) -> Vec<(Vec<u8>, u64)> {
+        map.iter().map(|e| (e.key().key_ref().to_vec(), e.key().ts())).collect()
+    }
+
+    #[test]
+    fn garbage_tail_is_a_torn_write() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.wal");
+        let wal = Wal::create(&path).unwrap();
+        put(&wal, b"a", 1, b"1");
+        put(&wal, b"b", 2, b"2");
+        drop(wal);
+        let synced_len = std::fs::metadata(&path).unwrap().len();
+        // full-length records made of zeros, what a file extended but never written looks like
+        append(&path, &[0; 64]);
+
+        let map = SkipMap::new();
+        let (wal, _) = Wal::recover(&path, &map).unwrap();
+        assert_eq!(keys(&map), vec![(b"a".to_vec(), 1), (b"b".to_vec(), 2)]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), synced_len);
+
+        // what is written after the recovery is not hidden behind the garbage
+        put(&wal, b"c", 3, b"3");
+        drop(wal);
+        let map = SkipMap::new();
+        Wal::recover(&path, &map).unwrap();
+        assert_eq!(keys(&map).len(), 3);
+    }
+
+    #[test]
+    fn bad_record_before_a_good_one_is_corruption() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.wal");
+        let wal = Wal::create(&path).unwrap();
+        put(&wal, b"a", 1, b"1");
+        put(&wal, b"b", 2, b"2");
+        drop(wal);
+        // flip a byte of the value of the first record
+        let mut data = std::fs::read(&path).unwrap();
+        let first_value = data.iter().position(|&b| b == b'1').unwrap();
+        data[first_value] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+
+        let map = SkipMap::new();
+        assert!(Wal::recover(&path, &map).is_err());
+    }
+
+    #[test]
+    fn version_1_records_are_replayed_at_the_default_timestamp() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.wal");
+        // no header and no timestamps, an empty value is a delete
+        let mut data = Vec::new();
+        for (key, value) in [(&b"a"[..], &b"1"[..]), (b"b", b"")] {
+            let start = data.len();
+            data.put_u32(key.len() as u32);
+            data.put_slice(key);
+            data.put_u32(value.len() as u32);
+            data.put_slice(value);
+            let checksum = crc32fast::hash(&data[start..]);
+            data.put_u32(checksum);
+        }
+        std::fs::write(&path, &data).unwrap();
+
+        let map = SkipMap::new();
+        let (wal, _) = Wal::recover(&path, &map).unwrap();
+        assert_eq!(keys(&map), vec![(b"a".to_vec(), TS_DEFAULT), (b"b".to_vec(), TS_DEFAULT)]);
+        assert_eq!(map.front().unwrap().value().as_ref(), b"1");
+        assert!(map.back().unwrap().value().is_empty());
+        // records of the new format would be unreadable in an old file
+        assert!(wal.put_batch(&[(KeySlice::from_slice(b"c", 1), b"3")]).is_err());
+    }
+
+    #[test]
+    fn torn_header_is_an_empty_wal() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.wal");
+        std::fs::write(&path, &WAL_MAGIC.to_be_bytes()[..5]).unwrap();
+        let map = SkipMap::new();
+        let (wal, _) = Wal::recover(&path, &map).unwrap();
+        assert!(map.is_empty());
+        put(&wal, b"a", 1, b"1");
+        drop(wal);
+        Wal::recover(&path, &map).unwrap();
+        assert_eq!(keys(&map), vec![(b"a".to_vec(), 1)]);
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn unknown_version_is_rejected() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.wal");
+        let mut data = Vec::new();
+        data.put_u64(WAL_MAGIC);
+        data.put_u32(WAL_FORMAT_VERSION + 1);
+        std::fs::write(&path, &data).unwrap();
+        assert!(Wal::recover(&path, &SkipMap::new()).is_err());
+    }
+}